@@ -0,0 +1,128 @@
+use glam::{f32::*, *};
+
+// Extra images that can be written alongside the beauty render, all taken from whatever the
+// camera ray hits first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Aov {
+  // The material's base color.
+  Albedo,
+  // World space surface normal.
+  Normal,
+  // Distance from the camera along the ray.
+  Depth,
+  // World space hit position.
+  Position,
+  // Index of the shape in the scene file.
+  ObjectId,
+}
+
+// Rays per pixel for the AOVs. They settle down much faster than the beauty image does.
+pub const AOV_SAMPLES: u32 = 16;
+
+impl Aov {
+  // Used for EXR channel names and sidecar file names.
+  pub fn name(self) -> &'static str {
+    match self {
+      Aov::Albedo => "albedo",
+      Aov::Normal => "normal",
+      Aov::Depth => "depth",
+      Aov::Position => "position",
+      Aov::ObjectId => "objectid",
+    }
+  }
+
+  // Names of the EXR channels, following the usual conventions for each kind of data. Values
+  // past the number of channels are dropped.
+  pub fn channels(self) -> &'static [&'static str] {
+    match self {
+      Aov::Albedo => &["R", "G", "B"],
+      Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+      Aov::Depth => &["Z"],
+      Aov::ObjectId => &["id"],
+    }
+  }
+}
+
+// What the camera rays through one pixel hit first, averaged over however many rays hit
+// something. Pixels where every ray missed keep the defaults.
+#[derive(Debug, Clone, Copy)]
+pub struct AovPixel {
+  pub albedo: Vec3A,
+  pub normal: Vec3A,
+  pub depth: f32,
+  pub position: Vec3A,
+  // Averaging ids makes no sense, so this is whatever the first ray hit.
+  pub object_id: Option<u32>,
+}
+
+impl Default for AovPixel {
+  fn default() -> Self {
+    AovPixel {
+      albedo: Vec3A::ZERO,
+      normal: Vec3A::ZERO,
+      depth: f32::INFINITY,
+      position: Vec3A::ZERO,
+      object_id: None,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct AovBuffers {
+  pub width: u32,
+  pub height: u32,
+  pub pixels: Vec<AovPixel>,
+}
+
+impl AovBuffers {
+  // The raw values, for float formats. Missed pixels have infinite depth and an id of -1.
+  pub fn values(&self, aov: Aov) -> Vec<Vec3A> {
+    self
+      .pixels
+      .iter()
+      .map(|p| match aov {
+        Aov::Albedo => p.albedo,
+        Aov::Normal => p.normal,
+        Aov::Depth => Vec3A::splat(p.depth),
+        Aov::Position => p.position,
+        Aov::ObjectId => Vec3A::splat(p.object_id.map_or(-1., |id| id as f32)),
+      })
+      .collect()
+  }
+
+  // Something that can be looked at in an 8-bit image, with everything squeezed into 0..1.
+  // Depth is white at the nearest hit and fades with one over distance, so endless ground planes
+  // don't squash everything else to white. Position fills the box around nearly everything that
+  // was hit, leaving out the farthest few percent on each axis, and each object gets its own
+  // arbitrary color.
+  pub fn display(&self, aov: Aov) -> Vec<Vec3A> {
+    let hits = || self.pixels.iter().filter(|p| p.object_id.is_some());
+    match aov {
+      Aov::Albedo => self.values(aov),
+      Aov::Normal => self.pixels.iter().map(|p| if p.object_id.is_some() { p.normal * 0.5 + 0.5 } else { Vec3A::ZERO }).collect(),
+      Aov::Depth => {
+        let near = hits().map(|p| p.depth).fold(f32::INFINITY, f32::min);
+        self.pixels.iter().map(|p| Vec3A::splat(if p.depth > 0. { (near / p.depth).min(1.) } else { 1. })).collect()
+      }
+      Aov::Position => {
+        let mut axes = [0, 1, 2].map(|axis| hits().map(|p| p.position[axis]).collect::<Vec<f32>>());
+        let (mut min, mut max) = (Vec3A::ZERO, Vec3A::ONE);
+        for (axis, values) in axes.iter_mut().enumerate().filter(|(_, v)| !v.is_empty()) {
+          values.sort_by(f32::total_cmp);
+          min[axis] = values[values.len() / 50];
+          max[axis] = values[values.len() - 1 - values.len() / 50];
+        }
+        let size = (max - min).max(Vec3A::splat(f32::MIN_POSITIVE));
+        self.pixels.iter().map(|p| if p.object_id.is_some() { ((p.position - min) / size).clamp(Vec3A::ZERO, Vec3A::ONE) } else { Vec3A::ZERO }).collect()
+      }
+      Aov::ObjectId => self.pixels.iter().map(|p| p.object_id.map_or(Vec3A::ZERO, id_color)).collect(),
+    }
+  }
+}
+
+// A bright, arbitrary color for an object id, so neighbouring ids are easy to tell apart.
+fn id_color(id: u32) -> Vec3A {
+  let h = (id as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+  let channel = |shift: u32| 0.25 + 0.75 * ((h >> shift) & 0xff) as f32 / 255.;
+  vec3a(channel(40), channel(48), channel(56))
+}
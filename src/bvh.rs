@@ -0,0 +1,262 @@
+use crate::geom::*;
+
+use glam::{f32::*, *};
+
+const NUM_BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+// Relative cost of stepping through a node versus intersecting one item, for the SAH.
+const TRAVERSAL_COST: f32 = 1.0;
+// Traversal keeps a fixed-size stack, which is never deeper than the tree.
+const MAX_DEPTH: usize = 60;
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    min: Vec3A,
+    max: Vec3A,
+    // A leaf when count > 0, covering items[first..first + count]. Otherwise its children are
+    // the nodes at first and first + 1.
+    first: u32,
+    count: u32,
+}
+
+fn empty_bounds() -> (Vec3A, Vec3A) {
+    (Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY))
+}
+
+fn surface_area(min: Vec3A, max: Vec3A) -> f32 {
+    let d = (max - min).max(Vec3A::ZERO);
+    2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+}
+
+// A bounding volume hierarchy over a list of boxes, built with the binned surface area
+// heuristic. It only knows about item indices, so whoever owns the items does the actual
+// intersection tests.
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    items: Vec<u32>,
+}
+
+impl Bvh {
+    pub fn new(bounds: &[(Vec3A, Vec3A)]) -> Bvh {
+        let mut bvh = Bvh { nodes: Vec::new(), items: (0..bounds.len() as u32).collect() };
+        if !bounds.is_empty() {
+            let centroids: Vec<Vec3A> = bounds.iter().map(|&(lo, hi)| 0.5 * (lo + hi)).collect();
+            let (min, max) = empty_bounds();
+            bvh.nodes.push(BvhNode { min, max, first: 0, count: 0 });
+            bvh.build_node(0, 0, bounds.len(), 0, bounds, &centroids);
+        }
+        bvh
+    }
+
+    fn build_node(
+        &mut self,
+        node: usize,
+        first: usize,
+        count: usize,
+        depth: usize,
+        bounds: &[(Vec3A, Vec3A)],
+        centroids: &[Vec3A],
+    ) {
+        let items = &mut self.items[first..first + count];
+        let (mut min, mut max) = empty_bounds();
+        let (mut cmin, mut cmax) = empty_bounds();
+        for &i in items.iter() {
+            let (lo, hi) = bounds[i as usize];
+            min = min.min(lo);
+            max = max.max(hi);
+            cmin = cmin.min(centroids[i as usize]);
+            cmax = cmax.max(centroids[i as usize]);
+        }
+
+        let make_leaf = |nodes: &mut Vec<BvhNode>| {
+            nodes[node] = BvhNode { min, max, first: first as u32, count: count as u32 };
+        };
+
+        let extent = cmax - cmin;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        if count <= 1 || extent[axis] <= 0. || depth >= MAX_DEPTH {
+            make_leaf(&mut self.nodes);
+            return;
+        }
+
+        let bin_of = |c: Vec3A| {
+            (((c[axis] - cmin[axis]) / extent[axis] * NUM_BINS as f32) as usize).min(NUM_BINS - 1)
+        };
+        let mut bin_bounds = [empty_bounds(); NUM_BINS];
+        let mut bin_counts = [0usize; NUM_BINS];
+        for &i in items.iter() {
+            let b = bin_of(centroids[i as usize]);
+            let (lo, hi) = bounds[i as usize];
+            bin_bounds[b] = (bin_bounds[b].0.min(lo), bin_bounds[b].1.max(hi));
+            bin_counts[b] += 1;
+        }
+
+        // Sweep from the right to get the cost of everything past each split, then from the left.
+        let mut right_costs = [0.; NUM_BINS];
+        let (mut rmin, mut rmax) = empty_bounds();
+        let mut rcount = 0;
+        for b in (1..NUM_BINS).rev() {
+            rmin = rmin.min(bin_bounds[b].0);
+            rmax = rmax.max(bin_bounds[b].1);
+            rcount += bin_counts[b];
+            right_costs[b] = if rcount > 0 { rcount as f32 * surface_area(rmin, rmax) } else { f32::INFINITY };
+        }
+
+        let mut best_split = 0;
+        let mut best_cost = f32::INFINITY;
+        let (mut lmin, mut lmax) = empty_bounds();
+        let mut lcount = 0;
+        for b in 0..NUM_BINS - 1 {
+            lmin = lmin.min(bin_bounds[b].0);
+            lmax = lmax.max(bin_bounds[b].1);
+            lcount += bin_counts[b];
+            if lcount > 0 {
+                let cost = lcount as f32 * surface_area(lmin, lmax) + right_costs[b + 1];
+                if cost < best_cost {
+                    best_cost = cost;
+                    best_split = b;
+                }
+            }
+        }
+
+        let split_cost = TRAVERSAL_COST + best_cost / surface_area(min, max).max(f32::MIN_POSITIVE);
+        if !best_cost.is_finite() || (split_cost >= count as f32 && count <= MAX_LEAF_SIZE) {
+            make_leaf(&mut self.nodes);
+            return;
+        }
+
+        // Partition in place: everything in bins up to best_split goes to the left child.
+        let mut left_count = 0;
+        for j in 0..count {
+            if bin_of(centroids[items[j] as usize]) <= best_split {
+                items.swap(j, left_count);
+                left_count += 1;
+            }
+        }
+
+        let left = self.nodes.len();
+        let (emin, emax) = empty_bounds();
+        self.nodes.push(BvhNode { min: emin, max: emax, first: 0, count: 0 });
+        self.nodes.push(BvhNode { min: emin, max: emax, first: 0, count: 0 });
+        self.nodes[node] = BvhNode { min, max, first: left as u32, count: 0 };
+        self.build_node(left, first, left_count, depth + 1, bounds, centroids);
+        self.build_node(left + 1, first + left_count, count - left_count, depth + 1, bounds, centroids);
+    }
+
+    // Distance along the ray where it enters the node's box, if it does so before max_dist.
+    #[inline(always)]
+    fn enter_node(node: &BvhNode, origin: Vec3A, inv_dir: Vec3A, max_dist: f32) -> Option<f32> {
+        let a = (node.min - origin) * inv_dir;
+        let b = (node.max - origin) * inv_dir;
+        let near = a.min(b).max_element().max(0.);
+        let far = a.max(b).min_element().min(max_dist);
+        if near <= far {
+            Some(near)
+        } else {
+            None
+        }
+    }
+
+    // Finds the closest item along the ray. `intersect` tests a single item and returns the
+    // distance to it along with whatever the caller wants back, or None on a miss.
+    pub fn closest_hit<H>(&self, ray: Ray, mut intersect: impl FnMut(u32) -> Option<(f32, H)>) -> Option<(f32, H)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inv_dir = ray.direction.recip();
+        let mut best: Option<(f32, H)> = None;
+        let mut best_dist = f32::INFINITY;
+
+        let mut stack = [0u32; MAX_DEPTH + 2];
+        let mut stack_len = 0;
+        if Self::enter_node(&self.nodes[0], ray.origin, inv_dir, best_dist).is_some() {
+            stack[0] = 0;
+            stack_len = 1;
+        }
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let node = &self.nodes[stack[stack_len] as usize];
+            if node.count > 0 {
+                for &item in &self.items[node.first as usize..(node.first + node.count) as usize] {
+                    if let Some((dist, h)) = intersect(item) {
+                        if dist < best_dist {
+                            best_dist = dist;
+                            best = Some((dist, h));
+                        }
+                    }
+                }
+            } else {
+                let l = node.first;
+                let r = node.first + 1;
+                let dl = Self::enter_node(&self.nodes[l as usize], ray.origin, inv_dir, best_dist);
+                let dr = Self::enter_node(&self.nodes[r as usize], ray.origin, inv_dir, best_dist);
+                // Push the farther child first so the nearer one gets visited next.
+                match (dl, dr) {
+                    (Some(dl), Some(dr)) => {
+                        let (near, far) = if dl <= dr { (l, r) } else { (r, l) };
+                        stack[stack_len] = far;
+                        stack[stack_len + 1] = near;
+                        stack_len += 2;
+                    }
+                    (Some(_), None) => {
+                        stack[stack_len] = l;
+                        stack_len += 1;
+                    }
+                    (None, Some(_)) => {
+                        stack[stack_len] = r;
+                        stack_len += 1;
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn sphere_distance(ray: Ray, center: Vec3A, radius: f32) -> Option<f32> {
+        let oc = ray.origin - center;
+        let b = oc.dot(ray.direction);
+        let disc = b * b - (oc.length_squared() - radius * radius);
+        if disc < 0. {
+            return None;
+        }
+        [-b - disc.sqrt(), -b + disc.sqrt()].into_iter().find(|&t| t > 0.)
+    }
+
+    #[test]
+    fn closest_hit_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut point = |scale: f32| scale * (vec3a(rng.gen(), rng.gen(), rng.gen()) - 0.5);
+        let spheres: Vec<(Vec3A, f32)> = (0..500).map(|i| (point(20.), 0.1 + 0.01 * (i % 50) as f32)).collect();
+        let bounds: Vec<_> = spheres.iter().map(|&(c, r)| (c - Vec3A::splat(r), c + Vec3A::splat(r))).collect();
+        let bvh = Bvh::new(&bounds);
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let ray = Ray { origin: point(30.), direction: point(2.).normalize() };
+            let hit = |i: u32| {
+                let (center, radius) = spheres[i as usize];
+                sphere_distance(ray, center, radius).map(|t| (t, i))
+            };
+            let expected = (0..spheres.len() as u32).filter_map(hit).min_by(|a, b| a.0.total_cmp(&b.0));
+            assert_eq!(bvh.closest_hit(ray, hit), expected);
+            hits += expected.is_some() as u32;
+        }
+        assert!(hits > 200, "only {} rays hit anything", hits);
+    }
+}
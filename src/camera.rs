@@ -0,0 +1,114 @@
+use crate::geom::*;
+use crate::scene::*;
+
+use glam::{f32::*, *};
+use serde::{Deserialize, Serialize};
+
+// The shape of the lens opening, which is the shape out-of-focus highlights take on.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub enum ApertureShape {
+  #[default]
+  Circle,
+  // A regular polygon with `blades` corners, turned `rotation` degrees from having a corner
+  // straight up.
+  Polygon { blades: u32, rotation: f32 },
+}
+
+impl ApertureShape {
+  // A uniformly distributed point in the aperture, with a circumradius of 1.
+  pub fn sample(&self, u: Vec2) -> Vec2 {
+    match *self {
+      ApertureShape::Circle => concentric_sample_disk(u),
+      ApertureShape::Polygon { blades, rotation } => {
+        let blades = blades.max(3);
+        // All the triangles in the fan have the same area, so pick one with u.x and stretch
+        // what's left of it back out to 0..1.
+        let pick = u.x * blades as f32;
+        let i = (pick as u32).min(blades - 1);
+        let s = pick - i as f32;
+        let step = std::f32::consts::TAU / blades as f32;
+        let start = std::f32::consts::FRAC_PI_2 + rotation.to_radians() + i as f32 * step;
+        let a = vec2(start.cos(), start.sin());
+        let b = vec2((start + step).cos(), (start + step).sin());
+        let su = s.sqrt();
+        su * ((1. - u.y) * a + u.y * b)
+      }
+    }
+  }
+}
+
+// Shirley and Chiu's concentric mapping from the square to the disk, which keeps nearby points
+// nearby so it doesn't spoil the quasirandom sequences.
+fn concentric_sample_disk(u: Vec2) -> Vec2 {
+  let offset = 2. * u - Vec2::ONE;
+  if offset == Vec2::ZERO {
+    return Vec2::ZERO;
+  }
+  let (r, theta) = if offset.x.abs() > offset.y.abs() {
+    (offset.x, std::f32::consts::FRAC_PI_4 * (offset.y / offset.x))
+  } else {
+    (offset.y, std::f32::consts::FRAC_PI_2 - std::f32::consts::FRAC_PI_4 * (offset.x / offset.y))
+  };
+  r * vec2(theta.cos(), theta.sin())
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ThinLens {
+  pub aperture_radius: f32,
+  // Distance along the view direction to the plane that's in perfect focus.
+  pub focus_distance: f32,
+  pub shape: ApertureShape,
+}
+
+// Turns positions on the image into rays into the scene. Without a lens it's a pinhole camera,
+// and everything is in focus.
+#[derive(Clone, Copy, Debug)]
+pub struct Camera {
+  eye_to_scene: Affine3A,
+  // Half the width and height of the image, on a plane one unit in front of the eye.
+  half_extent: Vec2,
+  lens: Option<ThinLens>,
+}
+
+impl Camera {
+  // v_fov is the vertical field of view in radians, and aspect is width over height.
+  pub fn new(position: Vec3A, target: Vec3A, up: Vec3A, v_fov: f32, aspect: f32) -> Camera {
+    let scene_to_eye = Affine3A::look_at_lh(position.into(), target.into(), up.into());
+    let tan_half_v_fov = (0.5 * v_fov).tan();
+    Camera {
+      eye_to_scene: scene_to_eye.inverse(),
+      half_extent: vec2(aspect * tan_half_v_fov, tan_half_v_fov),
+      lens: None,
+    }
+  }
+
+  pub fn with_lens(self, lens: ThinLens) -> Camera {
+    Camera { lens: Some(lens), ..self }
+  }
+
+  pub fn position(&self) -> Vec3A {
+    self.eye_to_scene.translation
+  }
+
+  // `film` runs from (0, 0) at the top left of the image to (1, 1) at the bottom right. A lens
+  // picks its point on the aperture with the context's next rng2.
+  pub fn generate_ray(&self, film: Vec2, ctx: &mut TraceContext) -> Ray {
+    let on_plane = (2. * film - Vec2::ONE) * self.half_extent * vec2(1., -1.);
+    let view_dir = vec3a(on_plane.x, on_plane.y, 1.);
+
+    let (origin, direction) = match self.lens {
+      Some(lens) if lens.aperture_radius > 0. => {
+        let on_lens = lens.aperture_radius * lens.shape.sample(ctx.rng2());
+        let origin = vec3a(on_lens.x, on_lens.y, 0.);
+        let focus_point = view_dir * lens.focus_distance;
+        (origin, (focus_point - origin).normalize())
+      }
+      _ => (Vec3A::ZERO, view_dir.normalize()),
+    };
+
+    Ray {
+      origin: self.eye_to_scene.transform_point3a(origin),
+      direction: self.eye_to_scene.transform_vector3a(direction),
+    }
+  }
+}
@@ -0,0 +1,235 @@
+use crate::film::*;
+use crate::filter::*;
+
+use clap::ValueEnum;
+use glam::{f32::*, *};
+use std::{
+  fmt,
+  fs::File,
+  io::{self, BufReader, BufWriter, Read, Write},
+  path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 8] = b"ORBCKPT2";
+// Everything up to the end of the width and height.
+const HEADER_BYTES: u64 = 44;
+// A PixelStats and a Splat.
+const PIXEL_BYTES: u64 = 64;
+
+// Everything needed to carry on adding samples to a render: the accumulated film, and what it
+// was rendered with. Sample counts are in the film, and together with the seed they say exactly
+// which random numbers come next.
+pub struct Checkpoint {
+  pub scene_hash: u64,
+  pub seed: u64,
+  pub max_depth: i32,
+  pub filter: PixelFilter,
+  pub film: Film,
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+  Io(PathBuf, io::Error),
+  NotACheckpoint(PathBuf),
+  Mismatch(PathBuf, &'static str),
+}
+
+impl fmt::Display for CheckpointError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CheckpointError::Io(path, e) => write!(f, "checkpoint {}: {}", path.display(), e),
+      CheckpointError::NotACheckpoint(path) => write!(f, "{} isn't an orb-ponder checkpoint", path.display()),
+      CheckpointError::Mismatch(path, what) => {
+        write!(f, "can't resume from {}: it was rendered with a different {}", path.display(), what)
+      }
+    }
+  }
+}
+
+impl std::error::Error for CheckpointError {}
+
+// FNV-1a, which unlike the standard library's hasher is guaranteed not to change between
+// builds, so checkpoints stay usable.
+#[derive(Debug, Clone, Copy)]
+pub struct SceneHasher(u64);
+
+impl SceneHasher {
+  pub fn new() -> SceneHasher {
+    SceneHasher(0xcbf2_9ce4_8422_2325)
+  }
+
+  pub fn write(&mut self, bytes: &[u8]) {
+    self.0 = bytes.iter().fold(self.0, |h, &b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3));
+  }
+
+  pub fn finish(self) -> u64 {
+    self.0
+  }
+}
+
+impl Checkpoint {
+  // Writes to a temporary file first and then renames it, so being killed halfway through
+  // never costs the previous checkpoint.
+  pub fn save(&self, path: &Path) -> Result<(), CheckpointError> {
+    let temp = path.with_extension("tmp");
+    File::create(&temp)
+      .and_then(|file| self.write(&mut BufWriter::new(file)))
+      .map_err(|e| CheckpointError::Io(temp.clone(), e))?;
+    std::fs::rename(&temp, path).map_err(|e| CheckpointError::Io(path.to_owned(), e))
+  }
+
+  fn write(&self, out: &mut impl Write) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&self.scene_hash.to_le_bytes())?;
+    out.write_all(&self.seed.to_le_bytes())?;
+    out.write_all(&self.max_depth.to_le_bytes())?;
+    out.write_all(&(self.filter.kind as u32).to_le_bytes())?;
+    out.write_all(&self.filter.radius.to_le_bytes())?;
+    out.write_all(&self.film.width.to_le_bytes())?;
+    out.write_all(&self.film.height.to_le_bytes())?;
+    for p in &self.film.pixels {
+      for v in p.sum.to_array() {
+        out.write_all(&v.to_le_bytes())?;
+      }
+      out.write_all(&p.luminance_sum.to_le_bytes())?;
+      out.write_all(&p.luminance_sq_sum.to_le_bytes())?;
+      out.write_all(&p.count.to_le_bytes())?;
+    }
+    for s in &self.film.splats {
+      for v in s.raw_sum() {
+        out.write_all(&v.to_le_bytes())?;
+      }
+      out.write_all(&s.raw_weight().to_le_bytes())?;
+    }
+    out.flush()
+  }
+
+  pub fn load(path: &Path) -> Result<Checkpoint, CheckpointError> {
+    let file = File::open(path).map_err(|e| CheckpointError::Io(path.to_owned(), e))?;
+    let len = file.metadata().map_err(|e| CheckpointError::Io(path.to_owned(), e))?.len();
+    Self::read(&mut BufReader::new(file), len).map_err(|e| match e.kind() {
+      io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => CheckpointError::NotACheckpoint(path.to_owned()),
+      _ => CheckpointError::Io(path.to_owned(), e),
+    })
+  }
+
+  // `len` is the size of the whole file, which has to be exactly what the width and height say,
+  // so a damaged or hostile file can't ask for a huge film.
+  fn read(input: &mut impl Read, len: u64) -> io::Result<Checkpoint> {
+    fn bytes<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+      let mut b = [0; N];
+      input.read_exact(&mut b)?;
+      Ok(b)
+    }
+    if &bytes::<8>(input)? != MAGIC {
+      return Err(io::ErrorKind::InvalidData.into());
+    }
+    let scene_hash = u64::from_le_bytes(bytes(input)?);
+    let seed = u64::from_le_bytes(bytes(input)?);
+    let max_depth = i32::from_le_bytes(bytes(input)?);
+    let kind = u32::from_le_bytes(bytes(input)?) as usize;
+    let kind = *FilterKind::value_variants().get(kind).ok_or(io::ErrorKind::InvalidData)?;
+    let filter = PixelFilter { kind, radius: f32::from_le_bytes(bytes(input)?) };
+    let width = u32::from_le_bytes(bytes(input)?);
+    let height = u32::from_le_bytes(bytes(input)?);
+    let expected_len = (width as u64)
+      .checked_mul(height as u64)
+      .and_then(|count| count.checked_mul(PIXEL_BYTES))
+      .and_then(|pixels| pixels.checked_add(HEADER_BYTES));
+    if expected_len != Some(len) {
+      return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut film = Film::new(width, height);
+    for p in &mut film.pixels {
+      let x = f32::from_le_bytes(bytes(input)?);
+      let y = f32::from_le_bytes(bytes(input)?);
+      let z = f32::from_le_bytes(bytes(input)?);
+      p.sum = vec3a(x, y, z);
+      p.luminance_sum = f64::from_le_bytes(bytes(input)?);
+      p.luminance_sq_sum = f64::from_le_bytes(bytes(input)?);
+      p.count = u32::from_le_bytes(bytes(input)?);
+    }
+    for s in &mut film.splats {
+      let sum = [bytes(input)?, bytes(input)?, bytes(input)?].map(i64::from_le_bytes);
+      *s = Splat::from_raw(sum, i64::from_le_bytes(bytes(input)?));
+    }
+    Ok(Checkpoint { scene_hash, seed, max_depth, filter, film })
+  }
+
+  // Checks this can be carried on with the given scene and settings.
+  pub fn check_matches(
+    &self,
+    path: &Path,
+    scene_hash: u64,
+    width: u32,
+    height: u32,
+    max_depth: i32,
+    filter: PixelFilter,
+  ) -> Result<(), CheckpointError> {
+    let mismatch = |what| Err(CheckpointError::Mismatch(path.to_owned(), what));
+    if self.scene_hash != scene_hash {
+      mismatch("scene")
+    } else if (self.film.width, self.film.height) != (width, height) {
+      mismatch("image size")
+    } else if self.max_depth != max_depth {
+      mismatch("max depth")
+    } else if self.filter != filter {
+      mismatch("filter")
+    } else {
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn checkpoint() -> Checkpoint {
+    let mut film = Film::new(3, 2);
+    for (i, (p, s)) in film.pixels.iter_mut().zip(&film.splats).enumerate() {
+      p.add(vec3a(1., i as f32, 0.25));
+      s.add(vec3a(i as f32, 0.5, 2.), 0.75);
+    }
+    Checkpoint { scene_hash: 0x1234, seed: 99, max_depth: 7, filter: PixelFilter::new(FilterKind::Tent, None), film }
+  }
+
+  fn bytes(checkpoint: &Checkpoint) -> Vec<u8> {
+    let mut out = Vec::new();
+    checkpoint.write(&mut out).unwrap();
+    out
+  }
+
+  #[test]
+  fn round_trip() {
+    let saved = checkpoint();
+    let data = bytes(&saved);
+    let loaded = Checkpoint::read(&mut data.as_slice(), data.len() as u64).unwrap();
+    assert_eq!((loaded.scene_hash, loaded.seed, loaded.max_depth), (saved.scene_hash, saved.seed, saved.max_depth));
+    assert_eq!(loaded.filter, saved.filter);
+    assert_eq!((loaded.film.width, loaded.film.height), (3, 2));
+    for (a, b) in loaded.film.pixels.iter().zip(&saved.film.pixels) {
+      assert_eq!((a.sum, a.luminance_sum, a.luminance_sq_sum, a.count), (b.sum, b.luminance_sum, b.luminance_sq_sum, b.count));
+    }
+    for (a, b) in loaded.film.splats.iter().zip(&saved.film.splats) {
+      assert_eq!((a.raw_sum(), a.raw_weight()), (b.raw_sum(), b.raw_weight()));
+    }
+  }
+
+  #[test]
+  fn rejects_truncated_file() {
+    let data = bytes(&checkpoint());
+    let short = &data[..data.len() - 1];
+    assert!(Checkpoint::read(&mut &*short, short.len() as u64).is_err());
+    // Even claiming to be the full length, the data runs out.
+    assert!(Checkpoint::read(&mut &*short, data.len() as u64).is_err());
+  }
+
+  #[test]
+  fn rejects_huge_size_without_allocating() {
+    let mut data = bytes(&checkpoint());
+    data[36..44].copy_from_slice(&[0xff; 8]);
+    let result = Checkpoint::read(&mut data.as_slice(), data.len() as u64);
+    assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+  }
+}
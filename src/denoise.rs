@@ -0,0 +1,120 @@
+use crate::aov::*;
+use crate::film::*;
+use crate::geom::*;
+
+use glam::{f32::*, *};
+use rayon::prelude::*;
+
+// An edge-avoiding À-trous wavelet filter, along the lines of SVGF. Each pass blurs with a 5x5
+// B3 spline kernel whose taps are spread twice as far apart as the last pass's, and each tap is
+// weighted down when the surface normal, depth or brightness differs too much from the pixel
+// being filtered. Brightness differences are measured against how noisy the pixel is, so noisy
+// pixels get blurred more than ones that have already settled down.
+const PASSES: u32 = 5;
+const KERNEL: [f32; 5] = [1. / 16., 1. / 4., 3. / 8., 1. / 4., 1. / 16.];
+
+// How sharply each kind of difference cuts a tap off. Bigger is more forgiving for luminance and
+// depth, and less forgiving for normals.
+const SIGMA_LUMINANCE: f32 = 4.;
+const SIGMA_NORMAL: f32 = 64.;
+const SIGMA_DEPTH: f32 = 1.;
+
+// Stands in for the variance of pixels with too few samples to estimate it.
+const UNKNOWN_VARIANCE: f32 = 1e6;
+
+#[derive(Debug, Clone, Copy)]
+struct Guide {
+  normal: Vec3A,
+  depth: f32,
+  // How much depth changes from one pixel to the next here, so sloped surfaces aren't mistaken
+  // for edges.
+  depth_slope: f32,
+}
+
+// Returns a less noisy copy of the film's colors. Texture detail is kept by dividing out the
+// albedo before filtering and multiplying it back in afterward. Pixels where the camera saw
+// nothing are left exactly as they were.
+pub fn denoise(film: &Film, aovs: &AovBuffers) -> Vec<Vec3A> {
+  let (width, height) = (film.width as i32, film.height as i32);
+  let hit = |p: &AovPixel| p.object_id.is_some();
+  let albedo: Vec<Vec3A> = aovs
+    .pixels
+    .iter()
+    .map(|p| if hit(p) { p.albedo.max(Vec3A::splat(0.01)) } else { Vec3A::ONE })
+    .collect();
+
+  let guides: Vec<Option<Guide>> = (0..width * height)
+    .map(|i| {
+      let p = &aovs.pixels[i as usize];
+      let (x, y) = (i % width, i / width);
+      let depth_at = |dx: i32, dy: i32| {
+        let (qx, qy) = (x + dx, y + dy);
+        (qx >= 0 && qx < width && qy >= 0 && qy < height)
+          .then(|| &aovs.pixels[(qy * width + qx) as usize])
+          .filter(|q| hit(q))
+          .map_or(f32::INFINITY, |q| (q.depth - p.depth).abs())
+      };
+      let slope = |a: f32, b: f32| if a.min(b).is_finite() { a.min(b) } else { 0. };
+      hit(p).then(|| Guide {
+        normal: p.normal,
+        depth: p.depth,
+        depth_slope: slope(depth_at(-1, 0), depth_at(1, 0)).max(slope(depth_at(0, -1), depth_at(0, 1))),
+      })
+    })
+    .collect();
+
+  let mut color: Vec<Vec3A> = film.colors().iter().zip(&albedo).map(|(&c, &a)| c / a).collect();
+  let mut variance: Vec<f32> = film
+    .pixels
+    .iter()
+    .zip(&albedo)
+    .map(|(p, &a)| {
+      let error = p.relative_error() * luminance(p.mean()).max(0.01);
+      if error.is_finite() { (error / luminance(a)).powi(2) } else { UNKNOWN_VARIANCE }
+    })
+    .collect();
+
+  for pass in 0..PASSES {
+    let step = 1 << pass;
+    let (next_color, next_variance) = (0..width * height)
+      .into_par_iter()
+      .map(|i| {
+        let Some(guide) = guides[i as usize] else {
+          return (color[i as usize], variance[i as usize]);
+        };
+        let (x, y) = (i % width, i / width);
+        let center = luminance(color[i as usize]);
+        let luminance_scale = SIGMA_LUMINANCE * variance[i as usize].sqrt() + 1e-4;
+        let (mut color_sum, mut variance_sum, mut weight_sum) = (Vec3A::ZERO, 0., 0.);
+        for (ky, &hy) in KERNEL.iter().enumerate() {
+          for (kx, &hx) in KERNEL.iter().enumerate() {
+            let offset = ivec2(kx as i32 - 2, ky as i32 - 2) * step;
+            let (qx, qy) = (x + offset.x, y + offset.y);
+            if qx < 0 || qx >= width || qy < 0 || qy >= height {
+              continue;
+            }
+            let q = (qy * width + qx) as usize;
+            let Some(other) = guides[q] else { continue };
+            let normal = guide.normal.dot(other.normal).max(0.).powf(SIGMA_NORMAL);
+            let depth_scale = SIGMA_DEPTH * guide.depth_slope * offset.as_vec2().length() + 1e-3 * guide.depth;
+            let depth = (-(guide.depth - other.depth).abs() / depth_scale).exp();
+            let lum = (-(center - luminance(color[q])).abs() / luminance_scale).exp();
+            let w = hx * hy * normal * depth * lum;
+            color_sum += w * color[q];
+            variance_sum += w * w * variance[q];
+            weight_sum += w;
+          }
+        }
+        if weight_sum > 0. {
+          (color_sum / weight_sum, variance_sum / (weight_sum * weight_sum))
+        } else {
+          (color[i as usize], variance[i as usize])
+        }
+      })
+      .unzip();
+    color = next_color;
+    variance = next_variance;
+  }
+
+  color.iter().zip(&albedo).map(|(&c, &a)| c * a).collect()
+}
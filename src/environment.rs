@@ -0,0 +1,356 @@
+use crate::geom::*;
+use crate::scene::*;
+
+use glam::{f32::*, *};
+use image::codecs::hdr::HdrDecoder;
+use image::{ColorType, ImageFormat, ImageResult, Rgb32FImage};
+use std::{
+  f32::consts::{PI, TAU},
+  path::Path,
+};
+
+// Light arriving from infinitely far away, which is what a ray sees when it misses everything.
+pub trait Environment: std::fmt::Debug + dyn_clone::DynClone + Send + Sync {
+  fn radiance(&self, direction: Vec3A) -> Vec3A;
+
+  // Whether the scene should sample this as a light. Only environments that implement sample
+  // and pdf should ever say yes.
+  fn is_light(&self) -> bool {
+    false
+  }
+
+  // A direction towards the environment, picked with u. The pdf is over solid angle.
+  fn sample(&self, u: Vec2) -> Option<LightSample> {
+    None
+  }
+
+  // The pdf sample would have had for picking `direction`.
+  fn pdf(&self, direction: Vec3A) -> f32 {
+    0.
+  }
+}
+
+dyn_clone::clone_trait_object!(Environment);
+
+// The same color in every direction. Too dim to be worth sampling as a light, usually.
+#[derive(Debug, Clone)]
+pub struct ConstantEnvironment(pub Vec3A);
+
+impl Environment for ConstantEnvironment {
+  fn radiance(&self, direction: Vec3A) -> Vec3A {
+    self.0
+  }
+}
+
+// An equirectangular (latitude-longitude) image wrapped around the scene. The top row is
+// straight up (+Z), the middle of the left edge is towards -X, and the image reads left to
+// right as the camera turns to its right. It's importance sampled by luminance, so a small
+// bright sun in the image gets found quickly.
+#[derive(Debug, Clone)]
+pub struct ImageEnvironment {
+  width: usize,
+  height: usize,
+  pixels: Vec<Vec3A>,
+  map_to_world: Quat,
+  intensity: f32,
+  // Running total of each row's sampling weights, and of the rows' totals. The weights are
+  // luminance times sin(theta), since rows near the poles cover less of the sphere.
+  row_cdfs: Vec<f32>,
+  marginal_cdf: Vec<f32>,
+}
+
+// Loads an image as linear RGB. The image crate hands back Radiance .hdr files as 8-bit unless
+// we ask its decoder directly. Other floating point formats come through as they are, and
+// anything 8-bit is taken to be sRGB. `bytes` is the whole file, and `path` only says what kind
+// of image it is.
+pub fn load_linear_image(path: &Path, bytes: &[u8]) -> ImageResult<Rgb32FImage> {
+  let is_hdr = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("hdr"));
+  if is_hdr {
+    let decoder = HdrDecoder::new(bytes)?;
+    let (width, height) = (decoder.metadata().width, decoder.metadata().height);
+    let pixels = decoder.read_image_hdr()?;
+    return Ok(Rgb32FImage::from_fn(width, height, |x, y| pixels[(y * width + x) as usize]));
+  }
+  let image = image::load_from_memory_with_format(bytes, ImageFormat::from_path(path)?)?;
+  let linear = matches!(image.color(), ColorType::Rgb32F | ColorType::Rgba32F);
+  let mut image = image.into_rgb32f();
+  if !linear {
+    for p in image.pixels_mut() {
+      p.0 = gamma_to_linear_rgb(*p).to_array();
+    }
+  }
+  Ok(image)
+}
+
+impl ImageEnvironment {
+  pub fn load(path: &Path, bytes: &[u8], map_to_world: Quat, intensity: f32) -> ImageResult<ImageEnvironment> {
+    Ok(ImageEnvironment::new(&load_linear_image(path, bytes)?, map_to_world, intensity))
+  }
+
+  // `image` holds linear radiance.
+  pub fn new(image: &Rgb32FImage, map_to_world: Quat, intensity: f32) -> ImageEnvironment {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let pixels: Vec<Vec3A> = image.pixels().map(|p| Vec3A::from(p.0)).collect();
+
+    let mut row_cdfs = Vec::with_capacity(width * height);
+    let mut marginal_cdf = Vec::with_capacity(height);
+    let mut total = 0.;
+    for y in 0..height {
+      let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+      let mut row_total = 0.;
+      for x in 0..width {
+        row_total += luminance(pixels[y * width + x]).max(0.) * sin_theta;
+        row_cdfs.push(row_total);
+      }
+      total += row_total;
+      marginal_cdf.push(total);
+    }
+
+    ImageEnvironment { width, height, pixels, map_to_world, intensity, row_cdfs, marginal_cdf }
+  }
+
+  fn texel(&self, x: usize, y: usize) -> Vec3A {
+    self.pixels[y.min(self.height - 1) * self.width + x % self.width]
+  }
+
+  // Map direction to image coordinates in 0..1, and the other way around.
+  fn direction_to_uv(&self, direction: Vec3A) -> Vec2 {
+    let d = self.map_to_world.inverse() * Vec3::from(direction);
+    let u = ((-d.y).atan2(-d.x) / TAU).rem_euclid(1.);
+    let v = d.z.clamp(-1., 1.).acos() / PI;
+    vec2(u, v)
+  }
+
+  fn uv_to_direction(&self, uv: Vec2) -> Vec3A {
+    let phi = uv.x * TAU;
+    let theta = uv.y * PI;
+    let d = vec3(-theta.sin() * phi.cos(), -theta.sin() * phi.sin(), theta.cos());
+    (self.map_to_world * d).into()
+  }
+
+  // The pdf over image area, at the pixel uv falls in.
+  fn uv_pdf(&self, x: usize, y: usize) -> f32 {
+    let total = *self.marginal_cdf.last().unwrap_or(&0.);
+    if total <= 0. {
+      return 0.;
+    }
+    let row = &self.row_cdfs[y * self.width..(y + 1) * self.width];
+    let weight = row[x] - if x > 0 { row[x - 1] } else { 0. };
+    weight * (self.width * self.height) as f32 / total
+  }
+
+  fn uv_pdf_to_solid_angle(uv_pdf: f32, v: f32) -> f32 {
+    let sin_theta = (v * PI).sin();
+    if sin_theta > 0. { uv_pdf / (2. * PI * PI * sin_theta) } else { 0. }
+  }
+}
+
+// Finds which bucket of a running total u lands in, and how far through it.
+fn sample_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+  let total = *cdf.last().unwrap();
+  let pick = u * total;
+  let i = cdf.partition_point(|&c| c <= pick).min(cdf.len() - 1);
+  let start = if i > 0 { cdf[i - 1] } else { 0. };
+  let width = cdf[i] - start;
+  let t = if width > 0. { ((pick - start) / width).clamp(0., 1.) } else { 0.5 };
+  (i, t)
+}
+
+impl Environment for ImageEnvironment {
+  // Bilinear lookup, wrapping around horizontally.
+  fn radiance(&self, direction: Vec3A) -> Vec3A {
+    let uv = self.direction_to_uv(direction);
+    let p = uv * vec2(self.width as f32, self.height as f32) - Vec2::splat(0.5);
+    let f = p - p.floor();
+    let x0 = (p.x.floor() as isize).rem_euclid(self.width as isize) as usize;
+    let y0 = p.y.floor().max(0.) as usize;
+    let top = self.texel(x0, y0).lerp(self.texel(x0 + 1, y0), f.x);
+    let bottom = self.texel(x0, y0 + 1).lerp(self.texel(x0 + 1, y0 + 1), f.x);
+    self.intensity * top.lerp(bottom, f.y)
+  }
+
+  fn is_light(&self) -> bool {
+    self.intensity > 0. && self.marginal_cdf.last().is_some_and(|&t| t > 0.)
+  }
+
+  fn sample(&self, u: Vec2) -> Option<LightSample> {
+    if !self.is_light() {
+      return None;
+    }
+    let (y, ty) = sample_cdf(&self.marginal_cdf, u.y);
+    let (x, tx) = sample_cdf(&self.row_cdfs[y * self.width..(y + 1) * self.width], u.x);
+    let uv = vec2((x as f32 + tx) / self.width as f32, (y as f32 + ty) / self.height as f32);
+    let pdf = Self::uv_pdf_to_solid_angle(self.uv_pdf(x, y), uv.y);
+    if pdf <= 0. {
+      return None;
+    }
+    let direction = self.uv_to_direction(uv);
+    Some(LightSample { direction, radiance: self.radiance(direction), pdf })
+  }
+
+  fn pdf(&self, direction: Vec3A) -> f32 {
+    let uv = self.direction_to_uv(direction);
+    let x = ((uv.x * self.width as f32) as usize).min(self.width - 1);
+    let y = ((uv.y * self.height as f32) as usize).min(self.height - 1);
+    Self::uv_pdf_to_solid_angle(self.uv_pdf(x, y), uv.y)
+  }
+}
+
+// Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight" (SIGGRAPH 1999), with
+// a sun disk on top. Turbidity is how hazy the air is: 2 is very clear, 10 is thick haze.
+// Below the horizon the sky just carries on with its horizon color.
+#[derive(Debug, Clone)]
+pub struct SkyEnvironment {
+  sun_direction: Vec3A,
+  // 1 - cos of the sun's angular radius, kept like this since it's tiny.
+  sun_one_minus_cos: f32,
+  sun_radiance: Vec3A,
+  // Perez coefficients A to E for luminance Y and chromaticity x and y.
+  perez: [[f32; 5]; 3],
+  // Zenith Yxy, divided by the Perez function at the zenith so it can just be multiplied in.
+  zenith: Vec3A,
+  intensity: f32,
+}
+
+// Takes luminance from kcd/m^2 to the units the rest of the scene uses, so a clear midday sky
+// comes out about as bright as the Emitters in our scenes.
+const SKY_SCALE: f32 = 0.05;
+// Luminance of the sun before the atmosphere gets to it, in kcd/m^2.
+const SUN_LUMINANCE: f32 = 1.6e6;
+// How often sample picks the sun, when it's up.
+const SUN_SAMPLE_CHANCE: f32 = 0.5;
+
+fn perez_function(c: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+  (1. + c[0] * (c[1] / cos_theta).exp()) * (1. + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos().powi(2))
+}
+
+fn xyy_to_linear_srgb(y_luminance: f32, x: f32, y: f32) -> Vec3A {
+  if y <= 0. {
+    return Vec3A::ZERO;
+  }
+  let xyz = vec3a(x * y_luminance / y, y_luminance, (1. - x - y) * y_luminance / y);
+  let to_srgb = Mat3A::from_cols_array(&[
+    3.2406, -1.5372, -0.4986,
+    -0.9689, 1.8758, 0.0415,
+    0.0557, -0.2040, 1.0570,
+  ]).transpose();
+  to_srgb * xyz
+}
+
+impl SkyEnvironment {
+  // The sun's elevation is in degrees above the horizon, and its azimuth in degrees from +X
+  // towards +Y. sun_radius is the angular radius of the disk, also in degrees.
+  pub fn new(elevation: f32, azimuth: f32, turbidity: f32, sun_radius: f32, intensity: f32) -> SkyEnvironment {
+    let t = turbidity.max(1.);
+    let (el, az) = (elevation.to_radians(), azimuth.to_radians());
+    let sun_direction = vec3a(el.cos() * az.cos(), el.cos() * az.sin(), el.sin());
+
+    // The model falls apart once the sun is below the horizon, so the sky stops changing there.
+    let theta_s = (std::f32::consts::FRAC_PI_2 - el).clamp(0., std::f32::consts::FRAC_PI_2 - 0.01);
+    let perez = [
+      [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+      [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+      [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+    ];
+    let chi = (4. / 9. - t / 120.) * (std::f32::consts::PI - 2. * theta_s);
+    let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+    let (t2, s, s2, s3) = (t * t, theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+    let zenith_x = t2 * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s)
+      + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s + 0.00394)
+      + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s + 0.25886);
+    let zenith_yc = t2 * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s)
+      + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s + 0.00516)
+      + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s + 0.26688);
+    let zenith = vec3a(
+      zenith_y.max(0.) / perez_function(&perez[0], 1., theta_s),
+      zenith_x / perez_function(&perez[1], 1., theta_s),
+      zenith_yc / perez_function(&perez[2], 1., theta_s),
+    );
+
+    // Sunlight loses blue to Rayleigh scattering and everything to haze, more so the more air
+    // it goes through. Kasten and Young's air mass, with wavelengths in micrometers.
+    let sun_radiance = if elevation > 0. {
+      let air_mass = 1. / (el.sin() + 0.50572 * (elevation + 6.07995).powf(-1.6364));
+      let beta = 0.04608 * t - 0.04586;
+      let transmittance = vec3a(0.68, 0.55, 0.44).to_array().map(|lambda: f32| {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = beta * lambda.powf(-1.3);
+        (-(rayleigh + aerosol) * air_mass).exp()
+      });
+      SKY_SCALE * SUN_LUMINANCE * Vec3A::from(transmittance)
+    } else {
+      Vec3A::ZERO
+    };
+
+    let half = 0.5 * sun_radius.to_radians();
+    SkyEnvironment {
+      sun_direction,
+      sun_one_minus_cos: 2. * half.sin() * half.sin(),
+      sun_radiance,
+      perez,
+      zenith,
+      intensity,
+    }
+  }
+
+  fn sky_radiance(&self, direction: Vec3A) -> Vec3A {
+    let cos_theta = direction.z.max(0.01);
+    let gamma = direction.dot(self.sun_direction).clamp(-1., 1.).acos();
+    let y = self.zenith.x * perez_function(&self.perez[0], cos_theta, gamma);
+    let x = self.zenith.y * perez_function(&self.perez[1], cos_theta, gamma);
+    let yc = self.zenith.z * perez_function(&self.perez[2], cos_theta, gamma);
+    SKY_SCALE * xyy_to_linear_srgb(y, x, yc).max(Vec3A::ZERO)
+  }
+
+  fn in_sun(&self, direction: Vec3A) -> bool {
+    1. - direction.dot(self.sun_direction) <= self.sun_one_minus_cos
+  }
+
+  fn sun_chance(&self) -> f32 {
+    if self.sun_radiance != Vec3A::ZERO { SUN_SAMPLE_CHANCE } else { 0. }
+  }
+}
+
+impl Environment for SkyEnvironment {
+  fn radiance(&self, direction: Vec3A) -> Vec3A {
+    let sun = if self.in_sun(direction) { self.sun_radiance } else { Vec3A::ZERO };
+    self.intensity * (self.sky_radiance(direction) + sun)
+  }
+
+  fn is_light(&self) -> bool {
+    self.intensity > 0.
+  }
+
+  // Either a direction in the sun's disk, or one in the upper hemisphere, cosine weighted so
+  // the bright sky near the zenith gets more of them.
+  fn sample(&self, u: Vec2) -> Option<LightSample> {
+    let sun_chance = self.sun_chance();
+    let direction = if u.x < sun_chance {
+      let u = vec2(u.x / sun_chance, u.y);
+      let cos_theta = 1. - u.x * self.sun_one_minus_cos;
+      let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+      let phi = std::f32::consts::TAU * u.y;
+      let (t, b) = orthonormal_basis(self.sun_direction);
+      sin_theta * phi.cos() * t + sin_theta * phi.sin() * b + cos_theta * self.sun_direction
+    } else {
+      let u = vec2((u.x - sun_chance) / (1. - sun_chance), u.y);
+      cosine_sample_hemisphere(Vec3A::Z, u)
+    };
+    let pdf = self.pdf(direction);
+    if pdf <= 0. {
+      return None;
+    }
+    Some(LightSample { direction, radiance: self.radiance(direction), pdf })
+  }
+
+  fn pdf(&self, direction: Vec3A) -> f32 {
+    let sun_chance = self.sun_chance();
+    let sun_pdf = if self.in_sun(direction) {
+      1. / (std::f32::consts::TAU * self.sun_one_minus_cos)
+    } else {
+      0.
+    };
+    let sky_pdf = direction.z.max(0.) / std::f32::consts::PI;
+    sun_chance * sun_pdf + (1. - sun_chance) * sky_pdf
+  }
+}
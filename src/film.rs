@@ -0,0 +1,168 @@
+use crate::geom::*;
+
+use glam::{f32::*, *};
+use std::sync::atomic::{AtomicI64, Ordering};
+
+// Everything we keep about the samples that landed in one pixel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelStats {
+  pub sum: Vec3A,
+  // Luminance sums, for estimating how noisy the pixel still is.
+  pub luminance_sum: f64,
+  pub luminance_sq_sum: f64,
+  pub count: u32,
+}
+
+// Below this, pixels count as black when measuring relative error, so dark pixels don't get
+// chased forever.
+const MIN_ERROR_LUMINANCE: f64 = 0.01;
+
+impl PixelStats {
+  pub fn add(&mut self, color: Vec3A) {
+    let l = luminance(color) as f64;
+    self.sum += color;
+    self.luminance_sum += l;
+    self.luminance_sq_sum += l * l;
+    self.count += 1;
+  }
+
+  pub fn mean(&self) -> Vec3A {
+    if self.count > 0 { self.sum / self.count as f32 } else { Vec3A::ZERO }
+  }
+
+  // The standard error of the mean luminance, relative to the mean luminance. Roughly, how far
+  // off the pixel might still be, as a fraction of its brightness.
+  pub fn relative_error(&self) -> f32 {
+    if self.count < 2 {
+      return f32::INFINITY;
+    }
+    let n = self.count as f64;
+    let mean = self.luminance_sum / n;
+    let variance = ((self.luminance_sq_sum - n * mean * mean) / (n - 1.)).max(0.);
+    ((variance / n).sqrt() / mean.max(MIN_ERROR_LUMINANCE)) as f32
+  }
+}
+
+// Samples are added up in fixed point, with this many fractional bits. Integer addition gives
+// the same answer whatever order the samples arrive in, so renders are exactly repeatable
+// however many threads splat into the same pixel. That leaves room for sums up to about 3.4e10,
+// which is thousands of samples of even the sun. Past that they stick at the limit rather
+// than wrapping around, and only then can the order make a difference.
+const SPLAT_FRACTION_BITS: i32 = 28;
+
+// Filter-weighted sums of every sample that landed near a pixel, which can be added to from any
+// thread.
+#[derive(Debug, Default)]
+pub struct Splat {
+  sum: [AtomicI64; 3],
+  weight: AtomicI64,
+}
+
+// Out of range values, including infinities, saturate.
+fn to_fixed(v: f32) -> i64 {
+  (v as f64 * (1i64 << SPLAT_FRACTION_BITS) as f64).round() as i64
+}
+
+fn saturating_add(sum: &AtomicI64, v: i64) {
+  // The closure always returns Some, so this can't fail.
+  let _ = sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |s| Some(s.saturating_add(v)));
+}
+
+fn from_fixed(v: i64) -> f64 {
+  v as f64 / (1i64 << SPLAT_FRACTION_BITS) as f64
+}
+
+impl Splat {
+  pub fn add(&self, color: Vec3A, weight: f32) {
+    for (sum, v) in self.sum.iter().zip((color * weight).to_array()) {
+      saturating_add(sum, to_fixed(v));
+    }
+    saturating_add(&self.weight, to_fixed(weight));
+  }
+
+  // The weighted average, or None if hardly anything has landed here yet. Filters with negative
+  // lobes can leave the total weight near zero, which would blow up the average.
+  pub fn mean(&self) -> Option<Vec3A> {
+    let weight = from_fixed(self.weight.load(Ordering::Relaxed));
+    (weight > 1e-3).then(|| {
+      let [r, g, b] = self.raw_sum().map(|v| (from_fixed(v) / weight) as f32);
+      vec3a(r, g, b)
+    })
+  }
+
+  // The fixed point sums, for checkpoints.
+  pub fn raw_sum(&self) -> [i64; 3] {
+    [0, 1, 2].map(|i| self.sum[i].load(Ordering::Relaxed))
+  }
+
+  pub fn raw_weight(&self) -> i64 {
+    self.weight.load(Ordering::Relaxed)
+  }
+
+  pub fn from_raw(sum: [i64; 3], weight: i64) -> Splat {
+    Splat { sum: sum.map(AtomicI64::new), weight: AtomicI64::new(weight) }
+  }
+}
+
+impl Clone for Splat {
+  fn clone(&self) -> Self {
+    Splat::from_raw(self.raw_sum(), self.raw_weight())
+  }
+}
+
+// The image being rendered. `pixels` tracks each pixel's own samples, for deciding when it's
+// done and how noisy it is, and `splats` holds the filtered image, which samples from
+// neighbouring pixels contribute to as well.
+#[derive(Debug, Clone)]
+pub struct Film {
+  pub width: u32,
+  pub height: u32,
+  pub pixels: Vec<PixelStats>,
+  pub splats: Vec<Splat>,
+}
+
+impl Film {
+  pub fn new(width: u32, height: u32) -> Film {
+    let count = width as usize * height as usize;
+    Film {
+      width,
+      height,
+      pixels: vec![PixelStats::default(); count],
+      splats: (0..count).map(|_| Splat::default()).collect(),
+    }
+  }
+
+  pub fn colors(&self) -> Vec<Vec3A> {
+    self.pixels.iter().zip(&self.splats).map(|(p, s)| s.mean().unwrap_or_else(|| p.mean())).collect()
+  }
+
+  // How many samples each pixel got, as colors running from black through red and yellow to
+  // white at `max_samples`.
+  pub fn sample_heatmap(&self, max_samples: u32) -> Vec<Vec3A> {
+    self
+      .pixels
+      .iter()
+      .map(|p| {
+        let t = 3. * p.count as f32 / max_samples.max(1) as f32;
+        vec3a(t, t - 1., t - 2.).clamp(Vec3A::ZERO, Vec3A::ONE)
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn splat_sums_saturate_instead_of_wrapping() {
+    let splat = Splat::default();
+    for _ in 0..4 {
+      splat.add(Vec3A::splat(1e10), 1.);
+    }
+    splat.add(vec3a(f32::INFINITY, 1., 1.), 1.);
+    assert_eq!(splat.raw_sum()[0], i64::MAX);
+    assert!(splat.raw_sum().iter().all(|&v| v > 0));
+    assert!(splat.mean().unwrap().min_element() > 0.);
+  }
+}
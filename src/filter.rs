@@ -0,0 +1,114 @@
+use glam::{f32::*, *};
+use std::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FilterKind {
+  // Every sample in the pixel counts the same and nothing outside it counts at all. Sharp, but
+  // prone to jaggies.
+  Box,
+  Tent,
+  Gaussian,
+  // Mitchell-Netravali with B = C = 1/3. Sharp, with a slight ring around edges.
+  Mitchell,
+  BlackmanHarris,
+  // Windowed sinc. The sharpest of the lot, and the most ringing.
+  Lanczos,
+}
+
+impl FilterKind {
+  // A radius that suits the filter's shape, in pixels.
+  pub fn default_radius(self) -> f32 {
+    match self {
+      FilterKind::Box => 0.5,
+      FilterKind::Tent => 1.,
+      FilterKind::Gaussian => 1.5,
+      FilterKind::Mitchell | FilterKind::BlackmanHarris | FilterKind::Lanczos => 2.,
+    }
+  }
+}
+
+// How much a sample counts toward a pixel, given how far it landed from the pixel's center.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PixelFilter {
+  pub kind: FilterKind,
+  // In pixels. Samples farther than this from a pixel's center on either axis don't touch it.
+  pub radius: f32,
+}
+
+impl PixelFilter {
+  pub fn new(kind: FilterKind, radius: Option<f32>) -> PixelFilter {
+    PixelFilter { kind, radius: radius.unwrap_or_else(|| kind.default_radius()).max(0.5) }
+  }
+
+  // The filters are all separable, so this is just the 1D weight for each axis multiplied
+  // together. `offset` is in pixels.
+  pub fn evaluate(&self, offset: Vec2) -> f32 {
+    self.evaluate_1d(offset.x) * self.evaluate_1d(offset.y)
+  }
+
+  fn evaluate_1d(&self, x: f32) -> f32 {
+    let r = self.radius;
+    let x = x.abs();
+    // Exactly on the edge counts as outside, so a sample on the border between two pixels
+    // doesn't count fully toward both of them with the box filter.
+    if x >= r {
+      return 0.;
+    }
+    match self.kind {
+      FilterKind::Box => 1.,
+      FilterKind::Tent => 1. - x / r,
+      FilterKind::Gaussian => {
+        // Shifted down so it reaches zero at the radius instead of stopping abruptly.
+        let sigma = r / 3.;
+        let gaussian = |x: f32| (-x * x / (2. * sigma * sigma)).exp();
+        gaussian(x) - gaussian(r)
+      }
+      FilterKind::Mitchell => {
+        const B: f32 = 1. / 3.;
+        const C: f32 = 1. / 3.;
+        let t = 2. * x / r;
+        if t < 1. {
+          ((12. - 9. * B - 6. * C) * t * t * t + (-18. + 12. * B + 6. * C) * t * t + (6. - 2. * B)) / 6.
+        } else {
+          ((-B - 6. * C) * t * t * t + (6. * B + 30. * C) * t * t + (-12. * B - 48. * C) * t + (8. * B + 24. * C)) / 6.
+        }
+      }
+      FilterKind::BlackmanHarris => {
+        let t = 2. * PI * (0.5 + 0.5 * x / r);
+        0.35875 - 0.48829 * t.cos() + 0.14128 * (2. * t).cos() - 0.01168 * (3. * t).cos()
+      }
+      FilterKind::Lanczos => sinc(x) * sinc(x / r),
+    }
+  }
+}
+
+fn sinc(x: f32) -> f32 {
+  if x < 1e-5 { 1. } else { (PI * x).sin() / (PI * x) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use clap::ValueEnum;
+
+  #[test]
+  fn fades_to_zero_at_radius() {
+    for &kind in FilterKind::value_variants() {
+      for radius in [0.5, 1., 2.5] {
+        let filter = PixelFilter::new(kind, Some(radius));
+        // Gaussian is shifted down to meet zero, and Mitchell peaks at 8/9 with these B and C.
+        if !matches!(kind, FilterKind::Gaussian | FilterKind::Mitchell) {
+          assert!((filter.evaluate(Vec2::ZERO) - 1.).abs() < 1e-5, "{:?} at the center", kind);
+        }
+        for edge in [vec2(radius, 0.), vec2(0., -radius), vec2(radius, radius)] {
+          assert_eq!(filter.evaluate(edge), 0., "{:?} at {}", kind, edge);
+        }
+        // Box stops dead at the edge, which is the point of it.
+        if kind != FilterKind::Box {
+          let inside = filter.evaluate_1d(radius * (1. - 1e-4));
+          assert!(inside.abs() < 1e-3, "{:?} just inside the radius is {}", kind, inside);
+        }
+      }
+    }
+  }
+}
@@ -10,6 +10,7 @@ use glam::{ *, f32::* };
 use quasirandom::Qrng;
 use lerp::Lerp;
 use rand::{Rng, rngs::ThreadRng, thread_rng};
+use serde::Deserialize;
 
 
 #[derive(Clone, Copy, Debug)]
@@ -24,18 +25,38 @@ impl Ray {
     }
 }
 
+// Hits closer than this are the surface a ray just left, not something new.
+pub const MIN_HIT_DISTANCE: f32 = 0.0001;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Hit<'a> {
     pub world_pos: Vec3A,
     pub world_normal: Vec3A,
+    // The normal of the surface itself. It's the same as world_normal except on meshes with
+    // vertex normals, where world_normal is smoothed for shading. Light sampling works with the
+    // actual surface, so it uses this one.
+    pub geometric_normal: Vec3A,
     pub local_pos: Vec3A,
     pub local_normal: Vec3A,
+    pub uv: Vec2,
     pub material: &'a dyn Material,
     pub distance: f32,
     pub started_inside: bool,
     pub local_to_world: Mat3A
 }
 
+impl<'a> Hit<'a> {
+    // A ray leaving the hit point, nudged off the surface to whichever side it's heading, so it
+    // can't hit the surface it just left.
+    pub fn spawn_ray(&self, direction: Vec3A) -> Ray {
+        let side = if direction.dot(self.world_normal) >= 0. { 1. } else { -1. };
+        Ray {
+            origin: self.world_pos + (side * MIN_HIT_DISTANCE) * self.world_normal,
+            direction,
+        }
+    }
+}
+
 
 pub fn get_point_in_sphere(rng: &mut TraceContext) -> Vec3A {
   loop {
@@ -51,6 +72,176 @@ pub fn reflect(ray: Vec3A, normal: Vec3A) -> Vec3A {
 }
 
 
+// Two unit vectors perpendicular to n and each other. From Duff et al., "Building an
+// Orthonormal Basis, Revisited" (JCGT 2017).
+pub fn orthonormal_basis(n: Vec3A) -> (Vec3A, Vec3A) {
+  let sign = 1_f32.copysign(n.z);
+  let a = -1. / (sign + n.z);
+  let b = n.x * n.y * a;
+  (vec3a(1. + sign * n.x * n.x * a, sign * b, -sign * n.x), vec3a(b, sign + n.y * n.y * a, -n.y))
+}
+
+// A direction in the hemisphere around normal, with density cos(theta) / pi.
+pub fn cosine_sample_hemisphere(normal: Vec3A, u: Vec2) -> Vec3A {
+  let (t, b) = orthonormal_basis(normal);
+  let r = u.x.sqrt();
+  let phi = std::f32::consts::TAU * u.y;
+  let z = (1. - u.x).max(0.).sqrt();
+  (r * phi.cos() * t + r * phi.sin() * b + z * normal).normalize()
+}
+
+pub fn uniform_sample_sphere(u: Vec2) -> Vec3A {
+  let z = 1. - 2. * u.x;
+  let r = (1. - z * z).max(0.).sqrt();
+  let phi = std::f32::consts::TAU * u.y;
+  vec3a(r * phi.cos(), r * phi.sin(), z)
+}
+
+// Converts a density over surface area at `pos` into a density over solid angle seen from `from`.
+pub fn area_to_solid_angle_pdf(area_pdf: f32, from: Vec3A, pos: Vec3A, normal: Vec3A) -> f32 {
+  let to = pos - from;
+  let dist2 = to.length_squared();
+  let cos = (normal.dot(to) / dist2.sqrt()).abs();
+  if cos > 0. { area_pdf * dist2 / cos } else { 0. }
+}
+
+// Veach's power heuristic, the weight for a sample from the strategy with density a when it's
+// combined with one that has density b.
+pub fn power_heuristic(a: f32, b: f32) -> f32 {
+  let a2 = a * a;
+  let b2 = b * b;
+  if a2 + b2 > 0. { a2 / (a2 + b2) } else { 0. }
+}
+
+
+// Fraction of light reflected at a smooth boundary, for unpolarized light arriving at cos_i to
+// the normal. eta is the index of refraction on the incoming side over the one on the far side.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+  let cos_i = cos_i.clamp(0., 1.);
+  let sin_t2 = eta * eta * (1. - cos_i * cos_i);
+  if sin_t2 >= 1. {
+    return 1.;
+  }
+  let cos_t = (1. - sin_t2).sqrt();
+  let r_parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+  let r_perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+  0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+// Bends the incoming ray through a boundary with normal facing back against it, or returns None
+// when it's reflected instead (total internal reflection).
+pub fn refract(ray: Vec3A, normal: Vec3A, eta: f32) -> Option<Vec3A> {
+  let cos_i = -ray.dot(normal);
+  let sin_t2 = eta * eta * (1. - cos_i * cos_i);
+  if sin_t2 >= 1. {
+    return None;
+  }
+  Some(eta * ray + (eta * cos_i - (1. - sin_t2).sqrt()) * normal)
+}
+
+
+// How scene radiance gets squeezed into the 0..1 range of an 8-bit image. Exposure is in
+// stops, applied before the curve.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+pub enum ToneMapOperator {
+  // Hard clip at 1, so anything brighter goes flat.
+  Clamp,
+  // L / (1 + L) on luminance. Never quite reaches white.
+  Reinhard,
+  // Reinhard, stretched so luminance `white` maps to exactly 1.
+  ExtendedReinhard,
+  // Stephen Hill's fit of the ACES reference rendering and sRGB output transforms.
+  Aces,
+  // Troy Sobotka's AgX, using Benjamin Wrensch's polynomial fit of the default contrast curve.
+  Agx,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct ToneMap {
+  pub operator: ToneMapOperator,
+  pub exposure: f32,
+  pub white: f32,
+}
+
+impl Default for ToneMap {
+  fn default() -> ToneMap {
+    ToneMap { operator: ToneMapOperator::Clamp, exposure: 0., white: 4. }
+  }
+}
+
+impl ToneMap {
+  // Maps linear radiance to linear display values in 0..1, ready for the sRGB transfer.
+  pub fn apply(&self, color: Vec3A) -> Vec3A {
+    let c = color.max(Vec3A::ZERO) * self.exposure.exp2();
+    let mapped = match self.operator {
+      ToneMapOperator::Clamp => c,
+      ToneMapOperator::Reinhard => scale_luminance(c, |l| l / (1. + l)),
+      ToneMapOperator::ExtendedReinhard => {
+        let white2 = (self.white * self.white).max(f32::MIN_POSITIVE);
+        scale_luminance(c, |l| l * (1. + l / white2) / (1. + l))
+      }
+      ToneMapOperator::Aces => aces_fitted(c),
+      ToneMapOperator::Agx => agx(c),
+    };
+    mapped.clamp(Vec3A::ZERO, Vec3A::ONE)
+  }
+}
+
+pub fn luminance(c: Vec3A) -> f32 {
+  c.dot(vec3a(0.2126, 0.7152, 0.0722))
+}
+
+// Runs luminance through `curve` and scales the color to match, which keeps its hue.
+fn scale_luminance(c: Vec3A, curve: impl Fn(f32) -> f32) -> Vec3A {
+  let l = luminance(c);
+  if l > 0. { c * (curve(l) / l) } else { Vec3A::ZERO }
+}
+
+fn aces_fitted(c: Vec3A) -> Vec3A {
+  // sRGB into the ACES working space, with the RRT's saturation tweak folded in.
+  let input = Mat3A::from_cols_array(&[
+    0.59719, 0.35458, 0.04823,
+    0.07600, 0.90834, 0.01566,
+    0.02840, 0.13383, 0.83777,
+  ]).transpose();
+  let output = Mat3A::from_cols_array(&[
+    1.60475, -0.53108, -0.07367,
+    -0.10208, 1.10813, -0.00605,
+    -0.00327, -0.07276, 1.07602,
+  ]).transpose();
+  let v = input * c;
+  let a = v * (v + 0.0245786) - 0.000090537;
+  let b = v * (0.983729 * v + 0.432951) + 0.238081;
+  output * (a / b)
+}
+
+fn agx(c: Vec3A) -> Vec3A {
+  const MIN_EV: f32 = -12.47393;
+  const MAX_EV: f32 = 4.026069;
+  let inset = Mat3A::from_cols_array(&[
+    0.84247905, 0.042328242, 0.042375654,
+    0.0784336, 0.87846863, 0.0784336,
+    0.079223745, 0.07916613, 0.879143,
+  ]);
+  let outset = Mat3A::from_cols_array(&[
+    1.196879, -0.052896854, -0.052971635,
+    -0.09802088, 1.1519032, -0.09804345,
+    -0.09902974, -0.098961174, 1.1510737,
+  ]);
+  let v = (inset * c).max(Vec3A::splat(1e-10));
+  let encoded = vec3a(v.x.log2(), v.y.log2(), v.z.log2()).clamp(Vec3A::splat(MIN_EV), Vec3A::splat(MAX_EV));
+  let x = (encoded - MIN_EV) / (MAX_EV - MIN_EV);
+  let x2 = x * x;
+  let x4 = x2 * x2;
+  let curved = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+    - 0.00232;
+  // The curve's output is display encoded with a plain 2.2 gamma, so undo that to get back to
+  // linear for the sRGB transfer.
+  let display = (outset * curved).max(Vec3A::ZERO);
+  vec3a(display.x.powf(2.2), display.y.powf(2.2), display.z.powf(2.2))
+}
+
 pub fn linear_to_gamma_1(c: f32) -> f32 {
     if c > 0.0 {
         if c <= 0.0031308 { c * 12.92 }
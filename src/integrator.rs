@@ -0,0 +1,85 @@
+use crate::geom::*;
+use crate::materials::*;
+use crate::scene::*;
+
+use glam::{f32::*, *};
+
+// Follows one path from the camera, and returns the light that comes back along it.
+//
+// At every surface we add whatever it emits, sample a light directly, add in the punctual
+// lights, then let the material pick where to go next. Emission found by a Diffuse bounce and
+// light found by sampling lights are two ways of counting the same thing, so both get weighted
+// with the power heuristic.
+pub fn trace_path(scene: &Scene, camera_ray: Ray, ctx: &mut TraceContext) -> Vec3A {
+  let mut radiance = Vec3A::ZERO;
+  let mut throughput = Vec3A::ONE;
+  let mut ray = camera_ray;
+  // The pdf of the bounce that made this ray, when it came from a Diffuse lobe.
+  let mut bsdf_pdf: Option<f32> = None;
+
+  for depth in 0..=ctx.max_depth() {
+    let (shape, hit) = match scene.closest_shape_hit(ray) {
+      Some(found) => found,
+      None => {
+        let weight = match bsdf_pdf {
+          Some(pdf) => power_heuristic(pdf, scene.environment_pdf(ray.direction)),
+          None => 1.,
+        };
+        radiance += throughput * scene.background(ray) * weight;
+        break;
+      }
+    };
+    let outgoing = -ray.direction;
+
+    let emitted = hit.material.emitted(&hit, outgoing);
+    if emitted != Vec3A::ZERO {
+      let weight = match bsdf_pdf {
+        Some(pdf) => power_heuristic(pdf, scene.light_pdf(shape, ray.origin, &hit)),
+        None => 1.,
+      };
+      radiance += throughput * emitted * weight;
+    }
+
+    if depth == ctx.max_depth() {
+      break;
+    }
+
+    if let Some(light) = scene.sample_light(&hit, ctx) {
+      if light.radiance != Vec3A::ZERO {
+        let f = hit.material.evaluate(&hit, outgoing, light.direction);
+        if f != Vec3A::ZERO {
+          let cos = light.direction.dot(hit.world_normal).abs();
+          let weight = power_heuristic(light.pdf, hit.material.pdf(&hit, outgoing, light.direction));
+          radiance += throughput * f * light.radiance * (cos / light.pdf * weight);
+        }
+      }
+    }
+
+    // Bounces can never find punctual lights, so every one of them gets a shadow ray, with no
+    // weighting against anything.
+    for punctual in scene.punctual_lights() {
+      if let Some(light) = scene.punctual_light(punctual, &hit) {
+        if light.radiance != Vec3A::ZERO {
+          let f = hit.material.evaluate(&hit, outgoing, light.direction);
+          radiance += throughput * f * light.radiance * light.direction.dot(hit.world_normal).abs();
+        }
+      }
+    }
+
+    let sample = match hit.material.sample(&hit, outgoing, ctx) {
+      Some(sample) => sample,
+      None => break,
+    };
+    throughput *= sample.weight;
+    if throughput == Vec3A::ZERO {
+      break;
+    }
+    bsdf_pdf = match sample.lobe {
+      Lobe::Diffuse => Some(sample.pdf),
+      Lobe::Specular => None,
+    };
+    ray = hit.spawn_ray(sample.direction);
+  }
+
+  radiance
+}
@@ -0,0 +1,56 @@
+use glam::{f32::*, *};
+
+// Lights that are a single point or a single direction. Nothing can hit them by bouncing
+// around, so they only ever show up through shadow rays.
+#[derive(Debug, Clone, Copy)]
+pub enum PunctualLight {
+  // Shines equally in every direction, with `intensity` falling off with distance squared.
+  Point { position: Vec3A, intensity: Vec3A },
+  // A point light limited to a cone around `direction`. It's full strength out to cos_inner, and
+  // fades smoothly to nothing at cos_outer.
+  Spot { position: Vec3A, direction: Vec3A, intensity: Vec3A, cos_inner: f32, cos_outer: f32 },
+  // Infinitely far away, like the sun. `direction` is the way the light travels, and every
+  // surface facing it gets `irradiance`.
+  Directional { direction: Vec3A, irradiance: Vec3A },
+}
+
+// Where a punctual light is, seen from a point, and how much light reaches that point if
+// nothing's in the way.
+#[derive(Debug, Clone, Copy)]
+pub struct Incident {
+  pub direction: Vec3A,
+  pub distance: f32,
+  pub radiance: Vec3A,
+}
+
+impl PunctualLight {
+  pub fn incident(&self, at: Vec3A) -> Option<Incident> {
+    match *self {
+      PunctualLight::Point { position, intensity } => {
+        let to_light = position - at;
+        let dist2 = to_light.length_squared();
+        let distance = dist2.sqrt();
+        (distance > 0.).then(|| Incident { direction: to_light / distance, distance, radiance: intensity / dist2 })
+      }
+      PunctualLight::Spot { position, direction, intensity, cos_inner, cos_outer } => {
+        let to_light = position - at;
+        let dist2 = to_light.length_squared();
+        let distance = dist2.sqrt();
+        if distance <= 0. {
+          return None;
+        }
+        let to_light = to_light / distance;
+        let cos = -to_light.dot(direction);
+        if cos <= cos_outer {
+          return None;
+        }
+        let t = ((cos - cos_outer) / (cos_inner - cos_outer).max(1e-6)).min(1.);
+        let falloff = t * t * (3. - 2. * t);
+        Some(Incident { direction: to_light, distance, radiance: intensity * (falloff / dist2) })
+      }
+      PunctualLight::Directional { direction, irradiance } => {
+        Some(Incident { direction: -direction, distance: f32::INFINITY, radiance: irradiance })
+      }
+    }
+  }
+}
@@ -2,17 +2,45 @@
 #![allow(unused_variables)]
 #![allow(unused_imports)]
 
+mod aov;
+mod bvh;
+mod camera;
+mod checkpoint;
+mod denoise;
+mod environment;
+mod film;
+mod filter;
 mod materials;
+mod noise;
+mod output;
+mod render;
+mod mesh;
+mod primitives;
 mod shapes;
+mod texture;
 mod geom;
+mod integrator;
+mod lights;
 mod scene;
+mod scene_file;
+mod sdf;
 
+use crate::aov::*;
+use crate::camera::*;
+use crate::checkpoint::*;
+use crate::denoise::*;
+use crate::film::*;
+use crate::filter::*;
 use crate::materials::*;
+use crate::output::*;
+use crate::render::*;
 use crate::shapes::*;
 use crate::geom::*;
+use crate::integrator::*;
 use crate::scene::*;
+use crate::scene_file::*;
 
-use std::{io::Cursor, collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
+use std::{path::{Path, PathBuf}, time::Instant, sync::{Arc, atomic::{AtomicBool, Ordering}}, io::Cursor, collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
 use image::buffer::ConvertBuffer;
 use image::io::Reader as ImageReader;
 use image::*;
@@ -26,9 +54,59 @@ use rayon::{ *, iter::* };
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
+    /// Samples per pixel, or the most any pixel gets with --noise-threshold
     #[clap(short, long, value_parser)]
     samples: Option<u32>,
 
+    /// Keep sampling each pixel until its relative error is below this, up to --samples
+    #[clap(long, value_parser)]
+    noise_threshold: Option<f32>,
+
+    /// Samples every pixel gets before --noise-threshold is checked
+    #[clap(long, value_parser, default_value_t = 16)]
+    min_samples: u32,
+
+    /// Also write an image of how many samples each pixel got
+    #[clap(long, value_parser)]
+    sample_map: Option<PathBuf>,
+
+    /// Render the whole image in passes of more and more samples, instead of a pixel at a time
+    #[clap(long)]
+    progressive: bool,
+
+    /// In progressive mode, write the image so far every this many seconds
+    #[clap(long, value_parser)]
+    snapshot_seconds: Option<f32>,
+
+    /// In progressive mode, write the image so far every this many passes
+    #[clap(long, value_parser)]
+    snapshot_passes: Option<u32>,
+
+    /// Stop after this many seconds, however many samples there are
+    #[clap(long, value_parser)]
+    time_limit: Option<f32>,
+
+    /// Save everything needed to carry on rendering to this file, every so often and on Ctrl-C.
+    /// Implies --progressive
+    #[clap(long, value_parser)]
+    checkpoint: Option<PathBuf>,
+
+    /// How often to save the checkpoint, in seconds
+    #[clap(long, value_parser, default_value_t = 300.)]
+    checkpoint_seconds: f32,
+
+    /// Carry on adding samples to the --checkpoint file, which must be of the same scene
+    #[clap(long, requires = "checkpoint")]
+    resume: bool,
+
+    /// Reconstruction filter that spreads each sample over the pixels around it
+    #[clap(long, value_enum, default_value = "box")]
+    filter: FilterKind,
+
+    /// How far the filter reaches, in pixels. Each filter has its own default
+    #[clap(long, value_parser)]
+    filter_radius: Option<f32>,
+
     #[clap(short, long, value_parser)]
     maxdepth: Option<u32>,
 
@@ -37,30 +115,43 @@ struct Cli {
 
     #[clap(short, long, value_parser)]
     height: Option<u32>,
-}
 
+    /// Scene description to render, as .ron, .json or .toml
+    #[clap(long, value_parser, default_value = "scenes/ponder.ron")]
+    scene: PathBuf,
 
-#[derive(Clone, Copy, Debug)]
-struct Viewport {
-    width: f32,
-    height: f32,
-    v_fov: f32
-}
+    /// Image to write. .exr, .hdr and .pfm keep the linear floating point values
+    #[clap(short, long, value_parser, default_value = "test.png")]
+    output: PathBuf,
 
+    /// Extra images of what the camera sees first, comma separated. They go in the same file for
+    /// .exr output, and beside it otherwise
+    #[clap(long, value_enum, value_delimiter = ',')]
+    aov: Vec<Aov>,
 
-impl Viewport {
-    fn pixel_to_dir(&self, pixel: Vec2) -> Vec3A {
-        let tan_half_v_fov = (0.5 * self.v_fov).tan();
-        let tan_half_h_fov = (self.width * tan_half_v_fov) / self.height;
-        let centered_pixel = (pixel + Vec2::splat(0.5)) - 0.5 * Vec2::new(self.width, self.height);
-        let norm_pixel = centered_pixel / Vec2::new(self.width, self.height);
-        let fov_pixel = norm_pixel * Vec2::new(2.0 * tan_half_h_fov, -2.0 * tan_half_v_fov);
-        let fwd = Vec3A::from((fov_pixel, 1.0));
-        let fwd_norm = fwd.normalize();
-        fwd_norm
-    }
+    /// Clean up the noise before saving, guided by the albedo, normals and depth. The image
+    /// without it is written too, as <output>.noisy.<ext>
+    #[clap(long)]
+    denoise: bool,
+
+    /// Seed for all the random numbers, so a render can be repeated exactly. Random if not given
+    #[clap(long, value_parser)]
+    seed: Option<u64>,
+
+    /// Tone mapping for 8-bit output. Overrides the scene file
+    #[clap(short, long, value_enum)]
+    tonemap: Option<ToneMapOperator>,
+
+    /// Exposure in stops, applied before tone mapping. Overrides the scene file
+    #[clap(short, long, value_parser, allow_hyphen_values = true)]
+    exposure: Option<f32>,
+
+    /// Luminance that extended Reinhard maps to white. Overrides the scene file
+    #[clap(long, value_parser)]
+    white: Option<f32>,
 }
 
+
 // fn get_point_in_sphere(rng: &mut ThreadRng) -> Vec3A {
 //     loop {
 //         let r = Vec3A::new(rng.gen(), rng.gen(), rng.gen()) * Vec3A::splat(2.0) - Vec3A::ONE;
@@ -108,132 +199,137 @@ fn main() {
     let cli = Cli::parse();
     let width = cli.width.or(cli.height).unwrap_or(512);
     let height = cli.height.or(cli.width).unwrap_or(512);
-    let mut dest = Rgb32FImage::new(width, height);
-
-    let scene_to_eye = Affine3A::look_at_lh(Vec3::new(-11., -7., 2.5), Vec3::new(0., 0., 4.), Vec3::Z);
-    let eye_to_scene = scene_to_eye.inverse();
-    let viewport = Viewport { width: width as f32, height: height as f32, v_fov: 45_f32.to_radians() };
-
-
-    let grey = Lambertian(Vec3A::new(0.5, 0.5, 0.5));
-    let yellow = Lambertian(Vec3A::new(0.4, 0.3, 0.1));
-    let gloss_floor = GlossWrap {
-        gloss_color: Vec3A::new(0.3, 0.225, 0.15),
-        diffuse_color: Vec3A::new(0., 0., 0.),
-        gloss_size: 0.06,
-        max_gloss: 1.0,
-        min_gloss: 1.0,
-        fresnel_power: 0.0
-    };
-    let yellow_floor = GlossWrap {
-        gloss_color: Vec3A::ONE,
-        diffuse_color: Vec3A::new(0.4, 0.3, 0.1),
-        gloss_size: 0.2,
-        max_gloss: 0.2,
-        min_gloss: 0.0,
-        fresnel_power: 5.0
-    };
-    let brushed_metal = BrushedMetal {
-        size: 1.0,
-        radial_roughness: 0.0,
-        circumference_roughness: 0.15,
-        color: Vec3A::new(0.8, 0.9, 1.0) * 0.2
-    };
-    let brushed_metal = BrushedMetal {
-        size: 1.0,
-        radial_roughness: 0.01,
-        circumference_roughness: 0.15,
-        color: Vec3A::new(0.8, 0.9, 1.0) * 0.5
+
+    let LoadedScene { scene, camera, mut tone_map, hash: scene_hash } = load_scene(&cli.scene).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    tone_map.operator = cli.tonemap.unwrap_or(tone_map.operator);
+    tone_map.exposure = cli.exposure.unwrap_or(tone_map.exposure);
+    tone_map.white = cli.white.unwrap_or(tone_map.white);
+
+    let camera = camera.camera(width as f32 / height as f32);
+
+    let num_aa = cli.samples.unwrap_or(10).max(1);
+    let max_depth = cli.maxdepth.unwrap_or(5).max(1) as i32;
+    let filter = PixelFilter::new(cli.filter, cli.filter_radius);
+    let resumed = match (&cli.checkpoint, cli.resume) {
+        (Some(path), true) => {
+            let checkpoint = Checkpoint::load(path)
+                .and_then(|c| c.check_matches(path, scene_hash, width, height, max_depth, filter).map(|_| c))
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                });
+            Some(checkpoint)
+        }
+        _ => None,
     };
-    let dim_red_floor = GlossWrap {
-      gloss_color: Vec3A::ONE,
-      diffuse_color: Vec3A::new(0.3, 0.05, 0.1),
-      gloss_size: 0.2,
-      max_gloss: 0.2,
-      min_gloss: 0.0,
-      fresnel_power: 5.0
-  };
-  let check = Checkerboard { size: 2.0, a: &dim_red_floor, b: &gloss_floor };
-  let photo_scale = 10_f32;
-
-    let textured = TexturedLambert::new(
-      ImageReader::open("andrew.jpg").unwrap().decode().expect("Couldn't load texture"),
-      Affine3A::from_cols(Vec3A::ZERO, vec3a(1. / photo_scale, 0., 0.), vec3a(0., -1. / photo_scale, 0.), vec3a(-0.5, 0., 0.)));
-
-    let sphere = GlossWrap {
-        gloss_color: Vec3A::ONE,
-        diffuse_color: Vec3A::new(0.35, 0.4, 0.5),
-        gloss_size: 0.05,
-        max_gloss: 1.0,
-        min_gloss: 0.02,
-        fresnel_power: 2.0
+
+    let settings = RenderSettings {
+        max_depth,
+        seed: resumed.as_ref().map(|c| c.seed).or(cli.seed).unwrap_or_else(|| thread_rng().gen()),
+        min_samples: cli.min_samples.min(num_aa),
+        max_samples: num_aa,
+        noise_threshold: cli.noise_threshold,
+        filter,
     };
-    let red = Lambertian(Vec3A::new(1.0, 0.0, 0.0));
-    let green_glow = Emitter { color: vec3a(0.4, 1.0, 0.4), focus: 1.0 };
-    let orb_glow = Emitter { color: vec3a(0.2, 0.3, 0.8) * 5., focus: 1.0 };
-    let white_glow = Emitter { color: vec3a(1.0, 1.0, 1.0) * 3., focus: 0.0 };
-
-    let scene = Scene {
-        shapes: vec![
-            Box::new(Sphere {
-                center: Vec3A::new(0., 0., 1.5),
-                radius: 1.5,
-                material: &orb_glow
-            }),
-            Box::new(Cuboid::new(
-                vec3a(3., 1.85, 0.),
-                Quat::from_rotation_z(20_f32.to_radians()),
-                vec3a(-0.3, -0.5 * photo_scale, 0.0),
-                vec3a(0.3, 0.5 * photo_scale, photo_scale),
-                &textured)),
-            Box::new(Sphere {
-                center: Vec3A::new(-1.25, -1.25, 0.5),
-                radius: 0.5,
-                material: &green_glow
-            }),
-            Box::new(Sphere {
-                center: Vec3A::new(-1.25, 1.25, 0.5),
-                radius: 0.5,
-                material: &red
+    let renderer = Renderer { scene: &scene, camera: &camera, settings };
+
+    let mut aov_list = cli.aov.clone();
+    aov_list.sort();
+    aov_list.dedup();
+    let aovs = (!aov_list.is_empty() || cli.denoise).then(|| renderer.render_aovs(width, height, num_aa.min(AOV_SAMPLES)));
+
+    let mut film = resumed.map_or_else(|| Film::new(width, height), |c| c.film);
+    let pixel_count = (width * height) as u64;
+    let save_images = |film: &Film| {
+        let colors = match &aovs {
+            Some(aovs) if cli.denoise => {
+                save_or_exit(&sidecar_path(&cli.output, "noisy"), width, height, &film.colors(), &tone_map);
+                denoise(film, aovs)
+            }
+            _ => film.colors(),
+        };
+        match &aovs {
+            Some(aovs) => save_image_with_aovs(&cli.output, &colors, &tone_map, aovs, &aov_list).unwrap_or_else(|e| {
+                eprintln!("Could not save {}: {}", cli.output.display(), e);
+                std::process::exit(1);
             }),
-            Box::new(Sphere {
-              center: Vec3A::new(-3.0, 2., 5.),
-              radius: 0.9,
-              material: &sphere
-          }),
-          Box::new(Plane::new(Vec3A::Z, Vec3A::X, Vec3A::ZERO, &check))
-            ]
+            None => save_or_exit(&cli.output, width, height, &colors, &tone_map),
+        }
+        if let Some(path) = &cli.sample_map {
+            save_or_exit(path, width, height, &film.sample_heatmap(num_aa), &ToneMap::default());
+        }
     };
-
-    let bar = indicatif::ProgressBar::new((width * height) as u64);
-    let num_aa = cli.samples.unwrap_or(10);
-    let max_depth = cli.maxdepth.unwrap_or(5).max(1) as i32;
-    let colors: Vec<_> = (0..(width * height)).into_par_iter().map(|pixel_number| {
-        let x = pixel_number % width;
-        let y = pixel_number / width;
-        let mut total_color = Vec3A::ZERO;
-        let mut trace_context = TraceContext::new(max_depth);
-        for _ in 0..num_aa {
-            let xy = Vec2::new(x as f32, y as f32) - Vec2::splat(0.5) + trace_context.rng2();
-            let view_dir = viewport.pixel_to_dir(xy);
-            let scene_ray = Ray {
-                origin: eye_to_scene.translation,
-                direction: eye_to_scene.transform_vector3a(view_dir)
-            };
-            let sample_color = scene.get_color(scene_ray, &mut trace_context);
-            trace_context.next_sample();
-            total_color += sample_color;
+    let save_checkpoint = |film: &Film| {
+        if let Some(path) = &cli.checkpoint {
+            let checkpoint = Checkpoint { scene_hash, seed: settings.seed, max_depth, filter, film: film.clone() };
+            if let Err(e) = checkpoint.save(path) {
+                eprintln!("{}", e);
+            }
         }
-        bar.inc(1);
-        total_color / num_aa as f32
-    }).collect();
-    bar.finish();
+    };
 
+    // When the render is meant to be stopped and carried on, the first Ctrl-C finishes up tidily,
+    // saving the checkpoint and image, and a second one gives up. Otherwise Ctrl-C just quits,
+    // without writing a half-finished image over whatever was there.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    if cli.progressive || cli.checkpoint.is_some() || cli.time_limit.is_some() {
+        let handler_flag = interrupted.clone();
+        ctrlc::set_handler(move || {
+            if handler_flag.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+        })
+        .expect("couldn't set the Ctrl-C handler");
+    }
+
+    let start = Instant::now();
+    let should_stop = || {
+        interrupted.load(Ordering::Relaxed)
+            || cli.time_limit.is_some_and(|limit| start.elapsed().as_secs_f32() >= limit)
+    };
 
-    for (&c, p) in colors.iter().zip(dest.pixels_mut()) {
-        *p = linear_to_gamma_rgb(c.into());
+    if cli.progressive || cli.checkpoint.is_some() {
+        let bar = indicatif::ProgressBar::new(pixel_count)
+            .with_style(indicatif::ProgressStyle::with_template("{msg} {wide_bar} {pos}/{len}").unwrap());
+        let mut last_snapshot = start;
+        let mut last_checkpoint = start;
+        let mut pass = 0;
+        let mut target = film.pixels.iter().map(|p| p.count).min().unwrap_or(0);
+        while !renderer.finished(&film) && !should_stop() {
+            target = next_pass_target(target, num_aa);
+            pass += 1;
+            bar.reset();
+            bar.set_message(format!("pass {}, {} spp", pass, target));
+            renderer.render_pass(&mut film, target, should_stop, || bar.inc(1));
+
+            let snapshot_due = cli.snapshot_passes.is_some_and(|n| pass % n.max(1) == 0)
+                || cli.snapshot_seconds.is_some_and(|secs| last_snapshot.elapsed().as_secs_f32() >= secs);
+            if snapshot_due {
+                save_images(&film);
+                last_snapshot = Instant::now();
+            }
+            if last_checkpoint.elapsed().as_secs_f32() >= cli.checkpoint_seconds {
+                save_checkpoint(&film);
+                last_checkpoint = Instant::now();
+            }
+        }
+        bar.finish();
+    } else {
+        let bar = indicatif::ProgressBar::new(pixel_count);
+        renderer.render_pass(&mut film, num_aa, should_stop, || bar.inc(1));
+        bar.finish();
     }
 
-    let rgb888: RgbImage = dest.convert();
-    rgb888.save("test.png").expect("Could not save image file");
+    save_checkpoint(&film);
+    save_images(&film);
+}
+
+fn save_or_exit(path: &Path, width: u32, height: u32, pixels: &[Vec3A], tone_map: &ToneMap) {
+    save_image(path, width, height, pixels, tone_map).unwrap_or_else(|e| {
+        eprintln!("Could not save {}: {}", path.display(), e);
+        std::process::exit(1);
+    });
 }
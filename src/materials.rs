@@ -1,6 +1,7 @@
 use crate::geom::*;
 use crate::scene::*;
 use crate::shapes::*;
+use crate::texture::*;
 
 use glam::{f32::*, *};
 use image::buffer::ConvertBuffer;
@@ -14,118 +15,226 @@ use std::{
   collections::hash_map::DefaultHasher,
   hash::{Hash, Hasher},
   io::Cursor,
+  sync::Arc,
 };
 
-pub trait Material: std::fmt::Debug + dyn_clone::DynClone + Sync {
-  fn get_color(&self, scene: &Scene, ray: Ray, hit: &Hit, ctx: &mut TraceContext) -> Vec3A;
+// What kind of scattering a BSDF sample came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lobe {
+  // Covered by evaluate() and pdf(), so it can be combined with light sampling.
+  Diffuse,
+  // Mirrors, glass, and blurred reflections we can't write a pdf for. evaluate() and pdf() leave
+  // these out, so whatever they hit gets counted in full.
+  Specular,
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Lambertian(pub Vec3A);
+#[derive(Debug, Clone, Copy)]
+pub struct BsdfSample {
+  pub direction: Vec3A,
+  // f * cos / pdf, what the light arriving from `direction` gets multiplied by.
+  pub weight: Vec3A,
+  pub pdf: f32,
+  pub lobe: Lobe,
+}
 
-impl Material for Lambertian {
-  fn get_color(&self, scene: &Scene, ray: Ray, hit: &Hit, ctx: &mut TraceContext) -> Vec3A {
-    if !ctx.try_push() {
-      return Vec3A::ZERO;
-    }
+// Directions follow the usual convention: `outgoing` points from the surface back toward
+// whoever's looking at it, and `incoming` points from the surface toward where light arrives
+// from. Both are unit length.
+pub trait Material: std::fmt::Debug + dyn_clone::DynClone + Send + Sync {
+  // Picks a direction to continue the path in, or None if the path stops here.
+  fn sample(&self, hit: &Hit, outgoing: Vec3A, ctx: &mut TraceContext) -> Option<BsdfSample>;
 
-    let color = self.0
-      * scene.get_color(
-        Ray {
-          origin: hit.world_pos,
-          direction: (hit.world_normal + 0.999 * get_point_in_sphere(ctx)).normalize(),
-        },
-        ctx,
-      );
-    ctx.pop();
-    color
+  // The BSDF f(outgoing, incoming) for the Diffuse lobes, without the cosine.
+  fn evaluate(&self, hit: &Hit, outgoing: Vec3A, incoming: Vec3A) -> Vec3A {
+    Vec3A::ZERO
+  }
+
+  // The solid angle pdf sample() would have had for picking `incoming` with a Diffuse lobe.
+  fn pdf(&self, hit: &Hit, outgoing: Vec3A, incoming: Vec3A) -> f32 {
+    0.
+  }
+
+  // Light given off by the surface toward `outgoing`.
+  fn emitted(&self, hit: &Hit, outgoing: Vec3A) -> Vec3A {
+    Vec3A::ZERO
+  }
+
+  fn is_emissive(&self) -> bool {
+    false
+  }
+
+  // Whether light passes through into the shape. Only these see the inside of a shape, since for
+  // anything opaque a hit from inside is a back face that no light should reach.
+  fn is_transmissive(&self) -> bool {
+    false
+  }
+
+  // The surface's base color, ignoring lighting and viewing angle. Only used for the albedo
+  // AOV, so it just needs to look right rather than be physically exact.
+  fn albedo(&self, hit: &Hit) -> Vec3A {
+    Vec3A::ZERO
   }
 }
 
-#[derive(Debug, Copy, Clone)]
+dyn_clone::clone_trait_object!(Material);
+
+// Shared by the materials with a plain Lambertian lobe, whose reflectance is `albedo` and which
+// gets picked with probability `lobe_chance`.
+fn diffuse_sample(hit: &Hit, albedo: Vec3A, lobe_chance: f32, ctx: &mut TraceContext) -> BsdfSample {
+  let direction = cosine_sample_hemisphere(hit.world_normal, ctx.rng2());
+  BsdfSample {
+    direction,
+    weight: albedo,
+    pdf: lobe_chance * diffuse_pdf(hit, direction),
+    lobe: Lobe::Diffuse,
+  }
+}
+
+fn diffuse_evaluate(hit: &Hit, albedo: Vec3A, outgoing: Vec3A, incoming: Vec3A) -> Vec3A {
+  if outgoing.dot(hit.world_normal) > 0. && incoming.dot(hit.world_normal) > 0. {
+    albedo * std::f32::consts::FRAC_1_PI
+  } else {
+    Vec3A::ZERO
+  }
+}
 
+fn diffuse_pdf(hit: &Hit, incoming: Vec3A) -> f32 {
+  incoming.dot(hit.world_normal).max(0.) * std::f32::consts::FRAC_1_PI
+}
+
+#[derive(Debug, Clone)]
+pub struct Lambertian(pub Arc<dyn Texture>);
+
+impl Material for Lambertian {
+  fn sample(&self, hit: &Hit, outgoing: Vec3A, ctx: &mut TraceContext) -> Option<BsdfSample> {
+    Some(diffuse_sample(hit, self.0.color(hit), 1., ctx))
+  }
+
+  fn evaluate(&self, hit: &Hit, outgoing: Vec3A, incoming: Vec3A) -> Vec3A {
+    diffuse_evaluate(hit, self.0.color(hit), outgoing, incoming)
+  }
+
+  fn pdf(&self, hit: &Hit, outgoing: Vec3A, incoming: Vec3A) -> f32 {
+    diffuse_pdf(hit, incoming)
+  }
+
+  fn albedo(&self, hit: &Hit) -> Vec3A {
+    self.0.color(hit)
+  }
+}
+
+#[derive(Debug, Clone)]
 pub struct GlossWrap {
-  pub gloss_color: Vec3A,
-  pub diffuse_color: Vec3A,
+  pub gloss_color: Arc<dyn Texture>,
+  pub diffuse_color: Arc<dyn Texture>,
   pub fresnel_power: f32,
-  pub gloss_size: f32,
+  pub gloss_size: Arc<dyn Texture>,
   pub max_gloss: f32,
   pub min_gloss: f32,
 }
 
-impl Material for GlossWrap {
-  fn get_color(&self, scene: &Scene, ray: Ray, hit: &Hit, ctx: &mut TraceContext) -> Vec3A {
-    if !ctx.try_push() {
-      return Vec3A::ZERO;
-    }
-
-    let fresnel = (1.0 + ray.direction.dot(hit.world_normal))
+impl GlossWrap {
+  // Chance of taking the gloss lobe rather than the diffuse one.
+  fn fresnel(&self, hit: &Hit, outgoing: Vec3A) -> f32 {
+    (1.0 - outgoing.dot(hit.world_normal))
       .clamp(0., 1.)
       .powf(self.fresnel_power)
-      .lerp(self.max_gloss, self.min_gloss);
+      .lerp(self.max_gloss, self.min_gloss)
+  }
+}
 
+impl Material for GlossWrap {
+  fn sample(&self, hit: &Hit, outgoing: Vec3A, ctx: &mut TraceContext) -> Option<BsdfSample> {
+    let fresnel = self.fresnel(hit, outgoing);
     let gloss_dir = reflect(
-      ray.direction,
-      ctx.blur_vector(hit.world_normal, self.gloss_size),
+      -outgoing,
+      ctx.blur_vector(hit.world_normal, self.gloss_size.value(hit)),
     );
-    let diffuse_dir = ctx.blur_vector(hit.world_normal, 1.0);
-    let color = if ctx.rng1() >= fresnel {
-      self.diffuse_color
-        * scene.get_color(
-          Ray {
-            origin: hit.world_pos,
-            direction: diffuse_dir,
-          },
-          ctx,
-        )
+    if ctx.rng1() >= fresnel {
+      Some(diffuse_sample(hit, self.diffuse_color.color(hit), 1. - fresnel, ctx))
     } else if gloss_dir.dot(hit.world_normal) > 0. {
-      self.gloss_color
-        * scene.get_color(
-          Ray {
-            origin: hit.world_pos,
-            direction: gloss_dir,
-          },
-          ctx,
-        )
+      Some(BsdfSample {
+        direction: gloss_dir,
+        weight: self.gloss_color.color(hit),
+        pdf: 0.,
+        lobe: Lobe::Specular,
+      })
     } else {
-      Vec3A::ZERO
-    };
-    ctx.pop();
-    color
+      None
+    }
+  }
+
+  fn evaluate(&self, hit: &Hit, outgoing: Vec3A, incoming: Vec3A) -> Vec3A {
+    (1. - self.fresnel(hit, outgoing)) * diffuse_evaluate(hit, self.diffuse_color.color(hit), outgoing, incoming)
+  }
+
+  fn pdf(&self, hit: &Hit, outgoing: Vec3A, incoming: Vec3A) -> f32 {
+    (1. - self.fresnel(hit, outgoing)) * diffuse_pdf(hit, incoming)
+  }
+
+  fn albedo(&self, hit: &Hit) -> Vec3A {
+    self.diffuse_color.color(hit)
   }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Checkerboard<'a> {
+#[derive(Debug, Clone)]
+pub struct Checkerboard {
   pub size: f32,
-  pub a: &'a dyn Material,
-  pub b: &'a dyn Material,
+  pub a: Arc<dyn Material>,
+  pub b: Arc<dyn Material>,
 }
 
-impl<'a> Material for Checkerboard<'a> {
-  fn get_color(&self, scene: &Scene, ray: Ray, hit: &Hit, ctx: &mut TraceContext) -> Vec3A {
+impl Checkerboard {
+  fn pick(&self, hit: &Hit) -> &dyn Material {
     let c = (hit.local_pos * Vec3A::splat(1. / self.size)).floor();
     if (c.x as i32 ^ c.y as i32 ^ c.z as i32) & 1 == 0 {
-      self.a.get_color(scene, ray, hit, ctx)
+      self.a.as_ref()
     } else {
-      self.b.get_color(scene, ray, hit, ctx)
+      self.b.as_ref()
     }
   }
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Material for Checkerboard {
+  fn sample(&self, hit: &Hit, outgoing: Vec3A, ctx: &mut TraceContext) -> Option<BsdfSample> {
+    self.pick(hit).sample(hit, outgoing, ctx)
+  }
+
+  fn evaluate(&self, hit: &Hit, outgoing: Vec3A, incoming: Vec3A) -> Vec3A {
+    self.pick(hit).evaluate(hit, outgoing, incoming)
+  }
+
+  fn pdf(&self, hit: &Hit, outgoing: Vec3A, incoming: Vec3A) -> f32 {
+    self.pick(hit).pdf(hit, outgoing, incoming)
+  }
+
+  fn emitted(&self, hit: &Hit, outgoing: Vec3A) -> Vec3A {
+    self.pick(hit).emitted(hit, outgoing)
+  }
+
+  fn is_emissive(&self) -> bool {
+    self.a.is_emissive() || self.b.is_emissive()
+  }
+
+  fn is_transmissive(&self) -> bool {
+    self.a.is_transmissive() || self.b.is_transmissive()
+  }
+
+  fn albedo(&self, hit: &Hit) -> Vec3A {
+    self.pick(hit).albedo(hit)
+  }
+}
+
+#[derive(Debug, Clone)]
 pub struct BrushedMetal {
   pub size: f32,
   pub radial_roughness: f32,
   pub circumference_roughness: f32,
-  pub color: Vec3A,
+  pub color: Arc<dyn Texture>,
 }
 
 impl Material for BrushedMetal {
-  fn get_color(&self, scene: &Scene, ray: Ray, hit: &Hit, ctx: &mut TraceContext) -> Vec3A {
-    if !ctx.try_push() {
-      return Vec3A::ZERO;
-    }
+  fn sample(&self, hit: &Hit, outgoing: Vec3A, ctx: &mut TraceContext) -> Option<BsdfSample> {
     let local = hit.local_pos * Vec3A::splat(1. / self.size);
     let center = local.floor() + Vec3A::splat(0.5);
     let offset = local - center;
@@ -136,97 +245,105 @@ impl Material for BrushedMetal {
       + circumference_dir * (sphere.y * self.circumference_roughness / radial_dir.length())
       + hit.local_normal * (sphere.z * 0.999);
     let world_normal = (hit.local_to_world * local_normal).normalize();
-    let reflected = reflect(ray.direction, world_normal);
-    let color = if reflected.dot(hit.world_normal) > 0. {
-      self.color
-        * scene.get_color(
-          Ray {
-            origin: hit.world_pos,
-            direction: reflected,
-          },
-          ctx,
-        )
+    let reflected = reflect(-outgoing, world_normal);
+    if reflected.dot(hit.world_normal) > 0. {
+      Some(BsdfSample {
+        direction: reflected,
+        weight: self.color.color(hit),
+        pdf: 0.,
+        lobe: Lobe::Specular,
+      })
     } else {
-      Vec3A::ZERO
-    };
+      None
+    }
+  }
 
-    ctx.pop();
-    color
+  fn albedo(&self, hit: &Hit) -> Vec3A {
+    self.color.color(hit)
   }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct Emitter {
-  pub color: Vec3A,
+  pub color: Arc<dyn Texture>,
   pub focus: f32,
 }
 
 impl Material for Emitter {
-  fn get_color(&self, scene: &Scene, ray: Ray, hit: &Hit, ctx: &mut TraceContext) -> Vec3A {
-    self.color
-      * (-ray.direction.dot(hit.world_normal))
+  fn sample(&self, hit: &Hit, outgoing: Vec3A, ctx: &mut TraceContext) -> Option<BsdfSample> {
+    None
+  }
+
+  fn emitted(&self, hit: &Hit, outgoing: Vec3A) -> Vec3A {
+    self.color.color(hit)
+      * outgoing.dot(hit.world_normal)
         .clamp(0.00001, 1.0)
         .powf(self.focus)
   }
+
+  fn is_emissive(&self) -> bool {
+    true
+  }
+
+  fn albedo(&self, hit: &Hit) -> Vec3A {
+    self.color.color(hit).min(Vec3A::ONE)
+  }
 }
 
-#[derive(Debug, Clone)]
-pub struct TexturedLambert {
-  width: i32,
-  height: i32,
-  local_to_pixel: Affine3A,
-  image_data: Vec<Vec3A>,
+// Glass, water and the like. The shape has to be closed, since which side of the surface we're
+// on comes from Hit::started_inside.
+#[derive(Debug, Copy, Clone)]
+pub struct Dielectric {
+  pub ior: f32,
+  // How far the surface normal gets blurred, like GlossWrap's gloss_size. 0 is perfectly smooth.
+  pub roughness: f32,
+  // Color of the light left after passing through one unit of the material, for Beer-Lambert
+  // absorption. ONE is perfectly clear.
+  pub tint: Vec3A,
 }
 
-impl TexturedLambert {
-  pub fn new(src_image: DynamicImage, local_to_image: Affine3A) -> TexturedLambert {
-    let src_image = src_image.into_rgb32f();
-    let w = src_image.width();
-    let h = src_image.height();
-    let mut d = Vec::<Vec3A>::with_capacity(((w + 1) * (h + 1)) as usize);
-    for (y, x) in (0..=h).cartesian_product(0..=w) {
-      d.push(gamma_to_linear_rgb(src_image[(x % w, y % h)]).into());
+impl Material for Dielectric {
+  fn sample(&self, hit: &Hit, outgoing: Vec3A, ctx: &mut TraceContext) -> Option<BsdfSample> {
+    let (normal, eta) = if hit.started_inside {
+      (-hit.world_normal, self.ior)
+    } else {
+      (hit.world_normal, 1. / self.ior)
+    };
+    let mut facet_normal = if self.roughness > 0. { ctx.blur_vector(normal, self.roughness) } else { normal };
+    if facet_normal.dot(outgoing) <= 0. {
+      facet_normal = normal;
     }
 
-    let local_to_pixel = Affine3A::from_scale(vec3(w as f32, h as f32, 0.)) * local_to_image;
-    TexturedLambert { width: w as i32, height: h as i32, local_to_pixel, image_data: d }
-  }
-}
+    let fresnel = fresnel_dielectric(outgoing.dot(facet_normal), eta);
+    let direction = match refract(-outgoing, facet_normal, eta) {
+      Some(refracted) if ctx.rng1() >= fresnel => refracted,
+      _ => reflect(-outgoing, facet_normal),
+    };
 
-impl Material for TexturedLambert {
-  fn get_color(&self, scene: &Scene, ray: Ray, hit: &Hit, ctx: &mut TraceContext) -> Vec3A {
-    if !ctx.try_push() {
-      return Vec3A::ZERO;
+    // A rough facet can send the ray back through the side it came from, which isn't light we
+    // can do anything with.
+    if (direction.dot(normal) > 0.) != (direction.dot(facet_normal) > 0.) {
+      return None;
     }
-    let uvw = self.local_to_pixel.transform_point3a(hit.local_pos);
-    let xyz = uvw.floor();
-    let frac1 = uvw - xyz;
-    let frac0 = Vec3A::ONE - frac1;
-    let x = (xyz.x as i32).rem_euclid(self.width);
-    let y = (xyz.y as i32).rem_euclid(self.height);
-    let i = y * (self.width + 1) + x;
-    let zz = self.image_data[0];
-
-    let diffuse_color =
-      ((frac0.x * frac0.y) * self.image_data[(i) as usize]) +
-      ((frac1.x * frac0.y) * self.image_data[(i + 1) as usize]) +
-      ((frac0.x * frac1.y) * self.image_data[(i + self.width + 1) as usize]) +
-      ((frac1.x * frac1.y) * self.image_data[(i + self.width + 2) as usize]);
-
-      let color = diffuse_color * 7.
-        * scene.get_color(
-          Ray {
-            origin: hit.world_pos,
-            direction: (hit.world_normal + 0.999 * get_point_in_sphere(ctx)).normalize(),
-          },
-          ctx,
-        );
-      ctx.pop();
-      color
-    }
-}
 
+    // The ray that got us here was travelling through the material, so it's been absorbed along
+    // the way.
+    let weight = if hit.started_inside {
+      Vec3A::from(self.tint.to_array().map(|t| t.max(1e-6).powf(hit.distance)))
+    } else {
+      Vec3A::ONE
+    };
+    Some(BsdfSample { direction, weight, pdf: 0., lobe: Lobe::Specular })
+  }
+
+  fn is_transmissive(&self) -> bool {
+    true
+  }
 
+  fn albedo(&self, hit: &Hit) -> Vec3A {
+    self.tint
+  }
+}
 
 /*
 fn get_color(r: Ray, max_depth: i32, rng: &mut ThreadRng) -> Vec3A {
@@ -0,0 +1,321 @@
+use crate::bvh::*;
+use crate::geom::*;
+use crate::materials::*;
+use crate::shapes::*;
+
+use glam::{f32::*, *};
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+#[derive(Debug, Clone, Copy)]
+struct MeshTriangle {
+    indices: [u32; 3],
+    material: u32,
+}
+
+// A ray set up for the watertight triangle test from Woop, Benthin and Wald, "Watertight
+// Ray/Triangle Intersection" (JCGT 2013). The ray is sheared so it points down +Z, which makes
+// the edge tests in the triangle's 2D projection exact enough that rays can't slip between
+// triangles that share an edge.
+#[derive(Debug, Clone, Copy)]
+struct WatertightRay {
+    origin: Vec3A,
+    kx: usize,
+    ky: usize,
+    kz: usize,
+    shear: Vec3A,
+}
+
+impl WatertightRay {
+    fn new(ray: Ray) -> WatertightRay {
+        let abs_dir = ray.direction.abs();
+        let kz = if abs_dir.x > abs_dir.y {
+            if abs_dir.x > abs_dir.z { 0 } else { 2 }
+        } else if abs_dir.y > abs_dir.z {
+            1
+        } else {
+            2
+        };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        if ray.direction[kz] < 0. {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        let shear = vec3a(
+            ray.direction[kx] / ray.direction[kz],
+            ray.direction[ky] / ray.direction[kz],
+            1. / ray.direction[kz],
+        );
+        WatertightRay { origin: ray.origin, kx, ky, kz, shear }
+    }
+
+    // Returns the distance along the ray and the barycentric weights of the three corners.
+    fn intersect(&self, p0: Vec3A, p1: Vec3A, p2: Vec3A) -> Option<(f32, Vec3A)> {
+        let (kx, ky, kz) = (self.kx, self.ky, self.kz);
+        let a = p0 - self.origin;
+        let b = p1 - self.origin;
+        let c = p2 - self.origin;
+        let ax = a[kx] - self.shear.x * a[kz];
+        let ay = a[ky] - self.shear.y * a[kz];
+        let bx = b[kx] - self.shear.x * b[kz];
+        let by = b[ky] - self.shear.y * b[kz];
+        let cx = c[kx] - self.shear.x * c[kz];
+        let cy = c[ky] - self.shear.y * c[kz];
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+
+        // Exactly on an edge, so redo it at higher precision to decide which side it's really on.
+        if u == 0. || v == 0. || w == 0. {
+            u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+            v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+            w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+        }
+
+        if (u < 0. || v < 0. || w < 0.) && (u > 0. || v > 0. || w > 0.) {
+            return None;
+        }
+        let det = u + v + w;
+        if det == 0. {
+            return None;
+        }
+
+        let t = u * (self.shear.z * a[kz]) + v * (self.shear.z * b[kz]) + w * (self.shear.z * c[kz]);
+        let distance = t / det;
+        if distance > MIN_HIT_DISTANCE {
+            Some((distance, vec3a(u, v, w) / det))
+        } else {
+            None
+        }
+    }
+}
+
+// A triangle mesh, loaded from a Wavefront OBJ file. Like the other shapes, it's treated as a
+// closed surface: normals point out, and hitting the back of a triangle means the ray started
+// inside.
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    positions: Vec<Vec3A>,
+    normals: Vec<Vec3A>,
+    uvs: Vec<Vec2>,
+    triangles: Vec<MeshTriangle>,
+    materials: Vec<Arc<dyn Material>>,
+    bvh: Bvh,
+    // Running total of triangle areas, for picking triangles when the mesh is sampled as a light.
+    area_cdf: Vec<f32>,
+}
+
+impl Mesh {
+    // Loads every object in the file as one mesh, baking in `transform`. OBJ materials named in
+    // `material_map` get the scene material they map to; everything else gets `material`. `bytes`
+    // is the whole OBJ file, and any .mtl it names is looked for next to `path`.
+    pub fn load_obj(
+        path: &Path,
+        bytes: &[u8],
+        transform: Affine3A,
+        material: Arc<dyn Material>,
+        material_map: &HashMap<String, Arc<dyn Material>>,
+    ) -> Result<Mesh, tobj::LoadError> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let (models, obj_materials) = tobj::load_obj_buf(
+            &mut std::io::Cursor::new(bytes),
+            &tobj::LoadOptions { single_index: true, triangulate: true, ..Default::default() },
+            |mtl_path| tobj::load_mtl(dir.join(mtl_path)),
+        )?;
+        // A missing or broken .mtl just means nothing gets mapped.
+        let obj_materials = obj_materials.unwrap_or_default();
+
+        let mut materials = vec![material];
+        let mut material_slots = HashMap::<usize, u32>::new();
+        let normal_transform = transform.matrix3.inverse().transpose();
+
+        let mut mesh = Mesh {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            triangles: Vec::new(),
+            materials: Vec::new(),
+            bvh: Bvh::default(),
+            area_cdf: Vec::new(),
+        };
+
+        // Normals and UVs are optional in OBJ, and we only keep them if every object has them.
+        let all_normals = models.iter().all(|m| !m.mesh.normals.is_empty());
+        let all_uvs = models.iter().all(|m| !m.mesh.texcoords.is_empty());
+
+        for model in &models {
+            let m = &model.mesh;
+            let base = mesh.positions.len() as u32;
+            let slot = match m.material_id {
+                Some(id) => *material_slots.entry(id).or_insert_with(|| {
+                    match obj_materials.get(id).and_then(|om| material_map.get(&om.name)) {
+                        Some(mapped) => {
+                            materials.push(mapped.clone());
+                            (materials.len() - 1) as u32
+                        }
+                        None => 0,
+                    }
+                }),
+                None => 0,
+            };
+
+            mesh.positions.extend(
+                m.positions
+                    .chunks_exact(3)
+                    .map(|p| transform.transform_point3a(vec3a(p[0], p[1], p[2]))),
+            );
+            if all_normals {
+                mesh.normals.extend(
+                    m.normals
+                        .chunks_exact(3)
+                        .map(|n| (normal_transform * vec3a(n[0], n[1], n[2])).normalize_or_zero()),
+                );
+            }
+            if all_uvs {
+                mesh.uvs.extend(m.texcoords.chunks_exact(2).map(|t| vec2(t[0], t[1])));
+            }
+            mesh.triangles.extend(m.indices.chunks_exact(3).map(|i| MeshTriangle {
+                indices: [base + i[0], base + i[1], base + i[2]],
+                material: slot,
+            }));
+        }
+
+        mesh.materials = materials;
+        let bounds: Vec<_> = mesh
+            .triangles
+            .iter()
+            .map(|t| {
+                let [p0, p1, p2] = t.indices.map(|i| mesh.positions[i as usize]);
+                (p0.min(p1).min(p2), p0.max(p1).max(p2))
+            })
+            .collect();
+        mesh.bvh = Bvh::new(&bounds);
+
+        let mut total_area = 0.;
+        mesh.area_cdf = mesh
+            .triangles
+            .iter()
+            .map(|t| {
+                let [p0, p1, p2] = t.indices.map(|i| mesh.positions[i as usize]);
+                total_area += 0.5 * (p1 - p0).cross(p2 - p0).length();
+                total_area
+            })
+            .collect();
+        Ok(mesh)
+    }
+
+    fn triangle_hit(&self, ray: Ray, triangle: &MeshTriangle, distance: f32, bary: Vec3A) -> Hit<'_> {
+        let [i0, i1, i2] = triangle.indices.map(|i| i as usize);
+        let p0 = self.positions[i0];
+        let p1 = self.positions[i1];
+        let p2 = self.positions[i2];
+        let mut geometric_normal = (p1 - p0).cross(p2 - p0).normalize_or_zero();
+
+        let normal = if self.normals.is_empty() {
+            geometric_normal
+        } else {
+            let n = (bary.x * self.normals[i0] + bary.y * self.normals[i1] + bary.z * self.normals[i2])
+                .normalize_or_zero();
+            // Trust the vertex normals over the winding order to say which side is outside.
+            if n.dot(geometric_normal) < 0. {
+                geometric_normal = -geometric_normal;
+            }
+            n
+        };
+
+        let uv = if self.uvs.is_empty() {
+            bary.yz()
+        } else {
+            bary.x * self.uvs[i0] + bary.y * self.uvs[i1] + bary.z * self.uvs[i2]
+        };
+
+        let world_pos = bary.x * p0 + bary.y * p1 + bary.z * p2;
+        Hit {
+            world_pos,
+            world_normal: normal,
+            geometric_normal,
+            local_pos: world_pos,
+            local_normal: normal,
+            uv,
+            material: self.materials[triangle.material as usize].as_ref(),
+            distance,
+            started_inside: geometric_normal.dot(ray.direction) > 0.,
+            local_to_world: Mat3A::IDENTITY,
+        }
+    }
+}
+
+impl Shape for Mesh {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
+        let wray = WatertightRay::new(ray);
+        let (distance, (bary, triangle)) = self.bvh.closest_hit(ray, |i| {
+            let triangle = &self.triangles[i as usize];
+            let [p0, p1, p2] = triangle.indices.map(|i| self.positions[i as usize]);
+            wray.intersect(p0, p1, p2).map(|(distance, bary)| (distance, (bary, triangle)))
+        })?;
+        Some(self.triangle_hit(ray, triangle, distance, bary))
+    }
+
+    fn is_light(&self) -> bool {
+        self.materials.iter().any(|m| m.is_emissive())
+    }
+
+    // Picks a triangle in proportion to its area, then a point on it. Parts of the mesh that
+    // don't emit get picked too, and just contribute nothing.
+    fn sample_surface(&self, from: Vec3A, u: Vec2) -> Option<SurfaceSample> {
+        let total_area = *self.area_cdf.last()?;
+        if total_area <= 0. {
+            return None;
+        }
+        let pick = u.x * total_area;
+        let i = self.area_cdf.partition_point(|&a| a <= pick).min(self.triangles.len() - 1);
+        let start = if i > 0 { self.area_cdf[i - 1] } else { 0. };
+        let s = ((pick - start) / (self.area_cdf[i] - start)).clamp(0., 1.);
+
+        let [p0, p1, p2] = self.triangles[i].indices.map(|i| self.positions[i as usize]);
+        let su = s.sqrt();
+        let position = (1. - su) * p0 + (u.y * su) * p1 + ((1. - u.y) * su) * p2;
+        let normal = (p1 - p0).cross(p2 - p0).normalize_or_zero();
+        Some(SurfaceSample {
+            position,
+            pdf: area_to_solid_angle_pdf(1. / total_area, from, position, normal),
+        })
+    }
+
+    fn surface_pdf(&self, from: Vec3A, hit: &Hit) -> f32 {
+        match self.area_cdf.last() {
+            Some(&total_area) if total_area > 0. => {
+                area_to_solid_angle_pdf(1. / total_area, from, hit.world_pos, hit.geometric_normal)
+            }
+            _ => 0.,
+        }
+    }
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let mut it = self.positions.iter();
+        let first = *it.next()?;
+        Some(it.fold((first, first), |(lo, hi), &p| (lo.min(p), hi.max(p))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // Rays aimed right along the edge two triangles share have to hit at least one of them.
+    #[test]
+    fn no_gaps_on_shared_edges() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let to_world = Affine3A::from_rotation_translation(Quat::from_euler(EulerRot::ZYX, 0.3, 0.7, 1.1), vec3(0.2, -3., 5.));
+        let [p0, p1, p2, p3] =
+            [vec3a(0., 0., 0.), vec3a(1., 0., 0.), vec3a(1., 1., 0.), vec3a(0., 1., 0.)].map(|p| to_world.transform_point3a(p));
+        for _ in 0..20000 {
+            let target = p0.lerp(p2, rng.gen());
+            let origin = target + vec3a(rng.gen(), rng.gen(), rng.gen()) * 10. - 5.;
+            let ray = WatertightRay::new(Ray { origin, direction: (target - origin).normalize() });
+            let hits = ray.intersect(p0, p1, p2).is_some() as u32 + ray.intersect(p0, p2, p3).is_some() as u32;
+            assert!(hits > 0, "slipped between the triangles at {}", target);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use glam::{f32::*, *};
+
+// How fast `perlin` can change, at most, per unit of distance. Anything that steps by distance
+// through noise needs to allow for this.
+pub const PERLIN_SLOPE: f32 = 2.5;
+
+// Improved Perlin gradient noise. Smooth, roughly -1..1, and zero at every integer point. The
+// gradients come from hashing the lattice point, so there's no table and no seed, and the same
+// point always gives the same value.
+pub fn perlin(p: Vec3A) -> f32 {
+  let cell = p.floor();
+  let f = p - cell;
+  let cell = cell.as_ivec3();
+  let fade = f * f * f * (f * (f * 6. - 15.) + 10.);
+  let corner = |x: i32, y: i32, z: i32| gradient(lattice_hash(cell + ivec3(x, y, z)), f - vec3a(x as f32, y as f32, z as f32));
+  let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+  lerp(
+    lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), fade.x), lerp(corner(0, 1, 0), corner(1, 1, 0), fade.x), fade.y),
+    lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), fade.x), lerp(corner(0, 1, 1), corner(1, 1, 1), fade.x), fade.y),
+    fade.z,
+  )
+}
+
+// Octaves of Perlin noise, each twice the frequency and half the strength of the last, scaled
+// back into roughly -1..1.
+pub fn fbm(p: Vec3A, octaves: u32) -> f32 {
+  let (mut sum, mut total, mut amplitude, mut frequency) = (0., 0., 1., 1.);
+  for _ in 0..octaves.max(1) {
+    sum += amplitude * perlin(p * frequency);
+    total += amplitude;
+    amplitude *= 0.5;
+    frequency *= 2.;
+  }
+  sum / total
+}
+
+// Like PERLIN_SLOPE, but for `fbm`. Every octave adds as much slope as the first, before
+// scaling back down.
+pub fn fbm_slope(octaves: u32) -> f32 {
+  let octaves = octaves.max(1);
+  PERLIN_SLOPE * octaves as f32 / (2. - 0.5f32.powi(octaves as i32 - 1))
+}
+
+fn lattice_hash(i: IVec3) -> u32 {
+  let mut h = (i.x as u32).wrapping_mul(0x8da6_b343) ^ (i.y as u32).wrapping_mul(0xd816_3841) ^ (i.z as u32).wrapping_mul(0xcb1a_b31f);
+  h ^= h >> 16;
+  h = h.wrapping_mul(0x7feb_352d);
+  h ^= h >> 15;
+  h = h.wrapping_mul(0x846c_a68b);
+  h ^ (h >> 16)
+}
+
+// Ken Perlin's twelve edge directions of a cube, dotted with `f`.
+fn gradient(hash: u32, f: Vec3A) -> f32 {
+  match hash % 12 {
+    0 => f.x + f.y,
+    1 => -f.x + f.y,
+    2 => f.x - f.y,
+    3 => -f.x - f.y,
+    4 => f.x + f.z,
+    5 => -f.x + f.z,
+    6 => f.x - f.z,
+    7 => -f.x - f.z,
+    8 => f.y + f.z,
+    9 => -f.y + f.z,
+    10 => f.y - f.z,
+    _ => -f.y - f.z,
+  }
+}
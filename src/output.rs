@@ -0,0 +1,119 @@
+use crate::aov::*;
+use crate::geom::*;
+
+use glam::{f32::*, *};
+use image::buffer::ConvertBuffer;
+use image::codecs::hdr::HdrEncoder;
+use image::*;
+use std::{
+  fs::File,
+  io::{BufWriter, Write},
+  path::{Path, PathBuf},
+};
+
+// Writes a linear radiance buffer, row by row from the top. The file extension picks the
+// format: .exr, .hdr and .pfm keep the raw floating point values, and anything else the image
+// crate knows about goes through `tone_map` and gets converted to 8-bit sRGB.
+pub fn save_image(path: &Path, width: u32, height: u32, pixels: &[Vec3A], tone_map: &ToneMap) -> ImageResult<()> {
+  match extension(path).as_deref() {
+    Some("exr") => {
+      let mut linear = Rgb32FImage::new(width, height);
+      for (&c, p) in pixels.iter().zip(linear.pixels_mut()) {
+        *p = Rgb(c.to_array());
+      }
+      linear.save(path)
+    }
+    Some("hdr") => {
+      let data: Vec<Rgb<f32>> = pixels.iter().map(|c| Rgb(c.max(Vec3A::ZERO).to_array())).collect();
+      HdrEncoder::new(BufWriter::new(File::create(path)?)).encode(&data, width as usize, height as usize)
+    }
+    Some("pfm") => save_pfm(path, width, height, pixels).map_err(ImageError::IoError),
+    _ => {
+      let mut dest = Rgb32FImage::new(width, height);
+      for (&c, p) in pixels.iter().zip(dest.pixels_mut()) {
+        *p = linear_to_gamma_rgb(tone_map.apply(c).into());
+      }
+      let rgb888: RgbImage = dest.convert();
+      rgb888.save(path)
+    }
+  }
+}
+
+// Writes the beauty image along with some AOVs. EXR output gets them as extra channels in the
+// same file, grouped by name like "normal.X". Other formats get a sidecar image per AOV next to
+// the main one, like render.normal.png, with the float formats keeping the raw values and
+// 8-bit ones squeezing them into something viewable.
+pub fn save_image_with_aovs(
+  path: &Path,
+  pixels: &[Vec3A],
+  tone_map: &ToneMap,
+  aovs: &AovBuffers,
+  which: &[Aov],
+) -> ImageResult<()> {
+  let (width, height) = (aovs.width, aovs.height);
+  let extension = extension(path);
+  if extension.as_deref() == Some("exr") {
+    return save_exr_layers(path, width, height, pixels, aovs, which);
+  }
+  save_image(path, width, height, pixels, tone_map)?;
+  let raw = matches!(extension.as_deref(), Some("hdr" | "pfm"));
+  for &aov in which {
+    let values = if raw { aovs.values(aov) } else { aovs.display(aov) };
+    save_image(&sidecar_path(path, aov.name()), width, height, &values, &ToneMap::default())?;
+  }
+  Ok(())
+}
+
+// Another image to go beside `path`, with `name` between the stem and extension.
+pub fn sidecar_path(path: &Path, name: &str) -> PathBuf {
+  let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+  let file_name = match path.extension().and_then(|e| e.to_str()) {
+    Some(e) => format!("{}.{}.{}", stem, name, e),
+    None => format!("{}.{}", stem, name),
+  };
+  path.with_file_name(file_name)
+}
+
+fn extension(path: &Path) -> Option<String> {
+  path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase())
+}
+
+fn save_exr_layers(path: &Path, width: u32, height: u32, pixels: &[Vec3A], aovs: &AovBuffers, which: &[Aov]) -> ImageResult<()> {
+  use exr::prelude::*;
+
+  let channel = |name: String, values: &[Vec3A], index: usize| {
+    AnyChannel::new(name.as_str(), FlatSamples::F32(values.iter().map(|v| v[index]).collect()))
+  };
+  let mut channels: Vec<_> = ["R", "G", "B"].iter().enumerate().map(|(i, &c)| channel(c.to_owned(), pixels, i)).collect();
+  for &aov in which {
+    let values = aovs.values(aov);
+    for (i, c) in aov.channels().iter().enumerate() {
+      channels.push(channel(format!("{}.{}", aov.name(), c), &values, i));
+    }
+  }
+
+  let layer = Layer::new(
+    (width as usize, height as usize),
+    LayerAttributes::default(),
+    Encoding::FAST_LOSSLESS,
+    AnyChannels::sort(SmallVec::from_vec(channels)),
+  );
+  Image::from_layer(layer)
+    .write()
+    .to_file(path)
+    .map_err(|e| ImageError::Encoding(error::EncodingError::new(ImageFormat::OpenExr.into(), e)))
+}
+
+// Portable float map: a tiny text header, then little-endian floats with the bottom row first.
+fn save_pfm(path: &Path, width: u32, height: u32, pixels: &[Vec3A]) -> std::io::Result<()> {
+  let mut out = BufWriter::new(File::create(path)?);
+  write!(out, "PF\n{} {}\n-1.0\n", width, height)?;
+  for row in pixels.chunks_exact(width as usize).rev() {
+    for c in row {
+      for v in c.to_array() {
+        out.write_all(&v.to_le_bytes())?;
+      }
+    }
+  }
+  out.flush()
+}
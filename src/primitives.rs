@@ -0,0 +1,535 @@
+use crate::geom::*;
+use crate::materials::*;
+use crate::shapes::*;
+
+use glam::{f32::*, *};
+use std::{
+    f32::consts::{PI, TAU},
+    sync::Arc,
+};
+
+// Where a shape sits in the world. The shapes here are all described around their own origin,
+// standing up along local +Z, and only ever moved and rotated, so distances are the same in
+// both spaces.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    local_to_world: Affine3A,
+    world_to_local: Affine3A,
+}
+
+impl Frame {
+    pub fn new(origin: Vec3A, orient: Quat) -> Frame {
+        let local_to_world = Affine3A::from_rotation_translation(orient, origin.into());
+        Frame { local_to_world, world_to_local: local_to_world.inverse() }
+    }
+
+    fn local_ray(&self, ray: Ray) -> Ray {
+        Ray {
+            origin: self.world_to_local.transform_point3a(ray.origin),
+            direction: self.world_to_local.transform_vector3a(ray.direction),
+        }
+    }
+
+    fn hit<'a>(&self, local: Ray, crossing: Crossing, uv: Vec2, material: &'a dyn Material, started_inside: bool) -> Hit<'a> {
+        let local_pos = local.at(crossing.distance);
+        let world_normal = self.local_to_world.matrix3 * crossing.normal;
+        Hit {
+            world_pos: self.local_to_world.transform_point3a(local_pos),
+            world_normal,
+            geometric_normal: world_normal,
+            local_pos,
+            local_normal: crossing.normal,
+            uv,
+            material,
+            distance: crossing.distance,
+            started_inside,
+            local_to_world: self.local_to_world.matrix3,
+        }
+    }
+
+    fn bounds(&self, lo: Vec3A, hi: Vec3A) -> (Vec3A, Vec3A) {
+        let center = self.local_to_world.transform_point3a(0.5 * (lo + hi));
+        let r = 0.5 * (hi - lo);
+        let m = self.local_to_world.matrix3;
+        let world_r = (m.x_axis * r.xxx()).abs() + (m.y_axis * r.yyy()).abs() + (m.z_axis * r.zzz()).abs();
+        (center - world_r, center + world_r)
+    }
+}
+
+// A point where a line crosses a surface, with the outward normal there in local space.
+#[derive(Debug, Clone, Copy)]
+struct Crossing {
+    distance: f32,
+    normal: Vec3A,
+}
+
+// Everywhere a line crosses a solid's surface, nearest first. They alternate between going in
+// and coming out, starting with going in. None of the shapes here get crossed more than four
+// times, which keeps this off the heap.
+#[derive(Debug, Clone, Copy, Default)]
+struct Crossings {
+    points: [Option<Crossing>; 4],
+    len: usize,
+}
+
+impl Crossings {
+    fn push(&mut self, distance: f32, normal: Vec3A) {
+        if self.len < self.points.len() {
+            self.points[self.len] = Some(Crossing { distance, normal });
+            self.len += 1;
+        }
+    }
+
+    fn get(&self, i: usize) -> Crossing {
+        self.points[i].unwrap()
+    }
+
+    // The first crossing in front of the ray, and whether the ray started inside.
+    fn first(&self) -> Option<(Crossing, bool)> {
+        (0..self.len & !1)
+            .map(|i| (self.get(i), i % 2 == 1))
+            .find(|(c, _)| c.distance > 0.)
+    }
+
+    fn spans(&self) -> impl Iterator<Item = (Crossing, Crossing)> + '_ {
+        (0..self.len / 2).map(|i| (self.get(2 * i), self.get(2 * i + 1)))
+    }
+}
+
+// One end of a stretch of a line, which might go on forever.
+#[derive(Debug, Clone, Copy)]
+struct End {
+    distance: f32,
+    // None at infinity.
+    normal: Option<Vec3A>,
+}
+
+impl End {
+    const NEG_INFINITY: End = End { distance: f32::NEG_INFINITY, normal: None };
+    const INFINITY: End = End { distance: f32::INFINITY, normal: None };
+}
+
+type Stretch = (End, End);
+
+// The parts of the line `t` where a t^2 + b t + c <= 0, which for a quadric surface is the inside.
+// There can be two, when the quadric opens out like a cone. `gradient` gives the outward normal
+// at a point on the surface, given its distance along the line.
+fn quadric_inside(a: f32, b: f32, c: f32, gradient: impl Fn(f32) -> Vec3A) -> [Option<Stretch>; 2] {
+    let end = |t: f32| End { distance: t, normal: Some(gradient(t).normalize_or_zero()) };
+    if a.abs() < 1e-9 {
+        return if b.abs() < 1e-9 {
+            [(c <= 0.).then_some((End::NEG_INFINITY, End::INFINITY)), None]
+        } else if b > 0. {
+            [Some((End::NEG_INFINITY, end(-c / b))), None]
+        } else {
+            [Some((end(-c / b), End::INFINITY)), None]
+        };
+    }
+    let disc = b * b - 4. * a * c;
+    if disc < 0. {
+        return [(a < 0.).then_some((End::NEG_INFINITY, End::INFINITY)), None];
+    }
+    // The numerically stable form, which avoids subtracting nearly equal numbers.
+    let q = -0.5 * (b + b.signum() * disc.sqrt());
+    let (t0, t1) = {
+        let (r0, r1) = (q / a, if q != 0. { c / q } else { q / a });
+        (r0.min(r1), r0.max(r1))
+    };
+    if a > 0. {
+        [Some((end(t0), end(t1))), None]
+    } else {
+        [Some((End::NEG_INFINITY, end(t0))), Some((end(t1), End::INFINITY))]
+    }
+}
+
+// Where the line is between the planes z = 0 and z = height.
+fn slab(local: Ray, height: f32) -> Option<Stretch> {
+    let (oz, dz) = (local.origin.z, local.direction.z);
+    if dz.abs() < 1e-9 {
+        return (oz >= 0. && oz <= height).then_some((End::NEG_INFINITY, End::INFINITY));
+    }
+    let bottom = End { distance: -oz / dz, normal: Some(Vec3A::NEG_Z) };
+    let top = End { distance: (height - oz) / dz, normal: Some(Vec3A::Z) };
+    Some(if dz > 0. { (bottom, top) } else { (top, bottom) })
+}
+
+fn overlap(a: Stretch, b: Stretch) -> Option<Stretch> {
+    let start = if a.0.distance >= b.0.distance { a.0 } else { b.0 };
+    let end = if a.1.distance <= b.1.distance { a.1 } else { b.1 };
+    (start.distance <= end.distance).then_some((start, end))
+}
+
+// Crossings for a convex shape, which a line can only pass through once.
+fn convex_crossings(stretch: Option<Stretch>) -> Crossings {
+    let mut crossings = Crossings::default();
+    if let Some((End { distance: t0, normal: Some(n0) }, End { distance: t1, normal: Some(n1) })) = stretch {
+        crossings.push(t0, n0);
+        crossings.push(t1, n1);
+    }
+    crossings
+}
+
+// Texture coordinates for points on the flat end of a round shape, across its whole diameter.
+fn cap_uv(local_pos: Vec3A, radius: f32) -> Vec2 {
+    local_pos.truncate() / (2. * radius) + 0.5
+}
+
+fn around_z(local_pos: Vec3A) -> f32 {
+    0.5 + local_pos.y.atan2(local_pos.x) / TAU
+}
+
+// Adds the usual Shape methods for the solids here, given a `crossings` method that finds
+// where a local ray crosses the surface, and a `uv` method for the texture coordinates there.
+macro_rules! solid_shape_methods {
+    () => {
+        fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
+            let local = self.frame.local_ray(ray);
+            let (crossing, started_inside) = self.crossings(local).first()?;
+            let uv = self.uv(local.at(crossing.distance), crossing.normal);
+            Some(self.frame.hit(local, crossing, uv, self.material.as_ref(), started_inside))
+        }
+
+        fn is_solid(&self) -> bool {
+            true
+        }
+
+        fn trace_spans(&self, ray: Ray) -> Vec<Span<'_>> {
+            let local = self.frame.local_ray(ray);
+            let material = self.material.as_ref();
+            self.crossings(local)
+                .spans()
+                .map(|(enter, exit)| Span {
+                    enter: self.frame.hit(local, enter, self.uv(local.at(enter.distance), enter.normal), material, false),
+                    exit: self.frame.hit(local, exit, self.uv(local.at(exit.distance), exit.normal), material, true),
+                })
+                .collect()
+        }
+    };
+}
+
+// A solid cylinder with flat ends, from z = 0 up to z = height.
+#[derive(Debug, Clone)]
+pub struct Cylinder {
+    pub frame: Frame,
+    pub radius: f32,
+    pub height: f32,
+    pub material: Arc<dyn Material>,
+}
+
+impl Cylinder {
+    fn crossings(&self, local: Ray) -> Crossings {
+        let (o, d) = (local.origin, local.direction);
+        let side = quadric_inside(
+            d.x * d.x + d.y * d.y,
+            2. * (o.x * d.x + o.y * d.y),
+            o.x * o.x + o.y * o.y - self.radius * self.radius,
+            |t| local.at(t) * vec3a(1., 1., 0.),
+        );
+        convex_crossings(side[0].zip(slab(local, self.height)).and_then(|(a, b)| overlap(a, b)))
+    }
+
+    fn uv(&self, local_pos: Vec3A, normal: Vec3A) -> Vec2 {
+        if normal.z != 0. {
+            cap_uv(local_pos, self.radius)
+        } else {
+            vec2(around_z(local_pos), local_pos.z / self.height)
+        }
+    }
+}
+
+impl Shape for Cylinder {
+    solid_shape_methods!();
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let r = self.radius;
+        Some(self.frame.bounds(vec3a(-r, -r, 0.), vec3a(r, r, self.height)))
+    }
+}
+
+// A solid cone with its round base at z = 0 and its point at z = height.
+#[derive(Debug, Clone)]
+pub struct Cone {
+    pub frame: Frame,
+    pub radius: f32,
+    pub height: f32,
+    pub material: Arc<dyn Material>,
+}
+
+impl Cone {
+    // The cone's surface is x^2 + y^2 = k^2 (height - z)^2, which also has a second cone
+    // balanced on the first one's point. That's above the slab, so clipping to the slab
+    // leaves only the one we want.
+    fn crossings(&self, local: Ray) -> Crossings {
+        let (o, d) = (local.origin, local.direction);
+        let k2 = (self.radius / self.height).powi(2);
+        let h = self.height - o.z;
+        let inside = quadric_inside(
+            d.x * d.x + d.y * d.y - k2 * d.z * d.z,
+            2. * (o.x * d.x + o.y * d.y + k2 * h * d.z),
+            o.x * o.x + o.y * o.y - k2 * h * h,
+            |t| {
+                let p = local.at(t);
+                vec3a(p.x, p.y, k2 * (self.height - p.z))
+            },
+        );
+        let slab = slab(local, self.height);
+        let stretch = inside.into_iter().flatten().find_map(|s| slab.and_then(|slab| overlap(s, slab)));
+        convex_crossings(stretch)
+    }
+
+    fn uv(&self, local_pos: Vec3A, normal: Vec3A) -> Vec2 {
+        if normal == Vec3A::NEG_Z {
+            cap_uv(local_pos, self.radius)
+        } else {
+            vec2(around_z(local_pos), local_pos.z / self.height)
+        }
+    }
+}
+
+impl Shape for Cone {
+    solid_shape_methods!();
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let r = self.radius;
+        Some(self.frame.bounds(vec3a(-r, -r, 0.), vec3a(r, r, self.height)))
+    }
+}
+
+// A cylinder from z = 0 to z = length with a half sphere on each end, or everywhere within
+// `radius` of that line segment.
+#[derive(Debug, Clone)]
+pub struct Capsule {
+    pub frame: Frame,
+    pub radius: f32,
+    pub length: f32,
+    pub material: Arc<dyn Material>,
+}
+
+impl Capsule {
+    // It's convex, so the line passes through it in one go, starting with whichever of the
+    // middle or the two end spheres it meets first and ending with whichever it leaves last.
+    fn crossings(&self, local: Ray) -> Crossings {
+        let (o, d) = (local.origin, local.direction);
+        let r2 = self.radius * self.radius;
+        let middle = quadric_inside(
+            d.x * d.x + d.y * d.y,
+            2. * (o.x * d.x + o.y * d.y),
+            o.x * o.x + o.y * o.y - r2,
+            |t| local.at(t) * vec3a(1., 1., 0.),
+        )[0]
+        .zip(slab(local, self.length))
+        .and_then(|(a, b)| overlap(a, b))
+        // Only the round side of the middle counts. The flat ends are inside the spheres.
+        .filter(|(a, b)| a.normal.is_some_and(|n| n.z == 0.) || b.normal.is_some_and(|n| n.z == 0.));
+        let sphere = |center: Vec3A| {
+            let oc = o - center;
+            quadric_inside(d.length_squared(), 2. * oc.dot(d), oc.length_squared() - r2, |t| local.at(t) - center)[0]
+        };
+        let pieces = [middle, sphere(Vec3A::ZERO), sphere(self.length * Vec3A::Z)];
+        let start = pieces.iter().flatten().map(|p| p.0).min_by(|a, b| a.distance.total_cmp(&b.distance));
+        let end = pieces.iter().flatten().map(|p| p.1).max_by(|a, b| a.distance.total_cmp(&b.distance));
+        convex_crossings(start.zip(end))
+    }
+
+    fn uv(&self, local_pos: Vec3A, normal: Vec3A) -> Vec2 {
+        let total = self.length + 2. * self.radius;
+        vec2(around_z(local_pos), ((local_pos.z + self.radius) / total).clamp(0., 1.))
+    }
+}
+
+impl Shape for Capsule {
+    solid_shape_methods!();
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let r = self.radius;
+        Some(self.frame.bounds(vec3a(-r, -r, -r), vec3a(r, r, self.length + r)))
+    }
+}
+
+// A ring doughnut lying in the XY plane around the origin. `major_radius` is out to the middle of
+// the tube, and `minor_radius` is the tube's own radius.
+#[derive(Debug, Clone)]
+pub struct Torus {
+    pub frame: Frame,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub material: Arc<dyn Material>,
+}
+
+impl Torus {
+    // The exact distance to the surface, negative inside the tube.
+    fn distance(&self, p: DVec3) -> f64 {
+        dvec2(p.truncate().length() - self.major_radius as f64, p.z).length() - self.minor_radius as f64
+    }
+
+    // The torus's equation along a line is a quartic, whose closed form solution is too
+    // fragile in floating point to trust. Instead this sphere traces the part of the line that's
+    // within the torus's bounding sphere: stepping by the distance to the surface can't jump
+    // over it, and only gets slow for rays that almost graze the tube. The last, tiny, step
+    // onto the surface goes just across it, which is where the distance changes sign.
+    fn crossings(&self, local: Ray) -> Crossings {
+        const MAX_STEPS: u32 = 10000;
+        let mut crossings = Crossings::default();
+        if self.minor_radius <= 0. {
+            return crossings;
+        }
+        let (o, d) = (local.origin.as_dvec3(), local.direction.as_dvec3());
+        let outer = (self.major_radius + self.minor_radius) as f64;
+        let b = o.dot(d);
+        let disc = b * b - (o.length_squared() - outer * outer);
+        if disc <= 0. {
+            return crossings;
+        }
+        let (near, far) = (-b - disc.sqrt(), -b + disc.sqrt());
+        let epsilon = 1e-6 * self.minor_radius as f64;
+        let f = |t: f64| self.distance(o + t * d);
+        let (mut t0, mut f0) = (near, f(near));
+        for _ in 0..MAX_STEPS {
+            let t1 = t0 + f0.abs().max(epsilon);
+            if t1 > far {
+                break;
+            }
+            let f1 = f(t1);
+            if (f0 < 0.) != (f1 < 0.) {
+                let t = t0 + (t1 - t0) * f0 / (f0 - f1);
+                crossings.push(t as f32, self.normal_at(local.at(t as f32)));
+            }
+            (t0, f0) = (t1, f1);
+        }
+        crossings
+    }
+
+    fn normal_at(&self, p: Vec3A) -> Vec3A {
+        let ring = vec3a(p.x, p.y, 0.).normalize_or_zero() * self.major_radius;
+        (p - ring).normalize_or_zero()
+    }
+
+    fn uv(&self, local_pos: Vec3A, normal: Vec3A) -> Vec2 {
+        let ring = vec3a(local_pos.x, local_pos.y, 0.).length() - self.major_radius;
+        vec2(around_z(local_pos), 0.5 + local_pos.z.atan2(ring) / TAU)
+    }
+}
+
+impl Shape for Torus {
+    solid_shape_methods!();
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let (big, small) = (self.major_radius + self.minor_radius, self.minor_radius);
+        Some(self.frame.bounds(vec3a(-big, -big, -small), vec3a(big, big, small)))
+    }
+}
+
+// Flat shapes in the local XY plane. They're surfaces rather than solids, and work from both
+// sides, so the normal always faces whoever's looking, and an emissive one shines both ways.
+fn flat_hit<'a>(frame: &Frame, ray: Ray, inside: impl Fn(Vec2) -> bool, uv: impl Fn(Vec2) -> Vec2, material: &'a dyn Material) -> Option<Hit<'a>> {
+    let local = frame.local_ray(ray);
+    if local.direction.z.abs() < 1e-9 {
+        return None;
+    }
+    let distance = -local.origin.z / local.direction.z;
+    let p = local.at(distance).truncate();
+    if !(distance > 0. && inside(p)) {
+        return None;
+    }
+    let normal = if local.direction.z > 0. { Vec3A::NEG_Z } else { Vec3A::Z };
+    Some(frame.hit(local, Crossing { distance, normal }, uv(p), material, false))
+}
+
+// A point uniformly spread over a flat shape of the given area, as a light sample seen from `from`.
+fn flat_sample(frame: &Frame, local_pos: Vec2, area: f32, from: Vec3A) -> Option<SurfaceSample> {
+    let position = frame.local_to_world.transform_point3a(local_pos.extend(0.).into());
+    let normal = frame.local_to_world.matrix3.z_axis;
+    let pdf = area_to_solid_angle_pdf(1. / area, from, position, normal);
+    (pdf > 0. && pdf.is_finite()).then_some(SurfaceSample { position, pdf })
+}
+
+// A flat disk with its center at the origin.
+#[derive(Debug, Clone)]
+pub struct Disk {
+    pub frame: Frame,
+    pub radius: f32,
+    pub material: Arc<dyn Material>,
+}
+
+impl Shape for Disk {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
+        let r = self.radius;
+        flat_hit(&self.frame, ray, |p| p.length_squared() <= r * r, |p| p / (2. * r) + 0.5, self.material.as_ref())
+    }
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let r = self.radius;
+        Some(self.frame.bounds(vec3a(-r, -r, 0.), vec3a(r, r, 0.)))
+    }
+
+    fn is_light(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_surface(&self, from: Vec3A, u: Vec2) -> Option<SurfaceSample> {
+        let p = self.radius * u.x.sqrt() * Vec2::from_angle(TAU * u.y);
+        flat_sample(&self.frame, p, PI * self.radius * self.radius, from)
+    }
+
+    fn surface_pdf(&self, from: Vec3A, hit: &Hit) -> f32 {
+        area_to_solid_angle_pdf(1. / (PI * self.radius * self.radius), from, hit.world_pos, hit.geometric_normal)
+    }
+}
+
+// A flat rectangle centered on the origin, `size.x` wide along local X and `size.y` along local Y.
+#[derive(Debug, Clone)]
+pub struct Rectangle {
+    pub frame: Frame,
+    pub size: Vec2,
+    pub material: Arc<dyn Material>,
+}
+
+impl Shape for Rectangle {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
+        let half = 0.5 * self.size;
+        flat_hit(&self.frame, ray, |p| p.abs().cmple(half).all(), |p| p / self.size + 0.5, self.material.as_ref())
+    }
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let half = 0.5 * self.size;
+        let half = Vec3A::from(half.extend(0.));
+        Some(self.frame.bounds(-half, half))
+    }
+
+    fn is_light(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_surface(&self, from: Vec3A, u: Vec2) -> Option<SurfaceSample> {
+        flat_sample(&self.frame, (u - 0.5) * self.size, self.size.x * self.size.y, from)
+    }
+
+    fn surface_pdf(&self, from: Vec3A, hit: &Hit) -> f32 {
+        area_to_solid_angle_pdf(1. / (self.size.x * self.size.y), from, hit.world_pos, hit.geometric_normal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::*;
+
+    // Straight through the middle, the line crosses the tube on both sides.
+    #[test]
+    fn torus_crossings_through_the_middle() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian(Arc::new(ConstantTexture(Vec3A::ONE))));
+        for (major_radius, minor_radius) in [(2., 0.5), (1., 0.001)] {
+            let frame = Frame::new(vec3a(1., 2., 3.), Quat::from_rotation_x(0.5));
+            let torus = Torus { frame, major_radius, minor_radius, material: material.clone() };
+            let direction = Quat::from_rotation_x(0.5) * Vec3A::X;
+            let ray = Ray { origin: vec3a(1., 2., 3.) - 5. * direction, direction };
+            let spans: Vec<_> = torus.trace_spans(ray).iter().map(|s| (s.enter.distance, s.exit.distance)).collect();
+            let (big, small) = (major_radius, minor_radius);
+            let expected = [(5. - big - small, 5. - big + small), (5. + big - small, 5. + big + small)];
+            assert_eq!(spans.len(), 2);
+            for ((enter, exit), (expected_enter, expected_exit)) in spans.into_iter().zip(expected) {
+                assert!((enter - expected_enter).abs() < 1e-5 && (exit - expected_exit).abs() < 1e-5);
+            }
+        }
+    }
+}
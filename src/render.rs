@@ -0,0 +1,130 @@
+use crate::aov::*;
+use crate::camera::*;
+use crate::film::*;
+use crate::filter::*;
+use crate::integrator::*;
+use crate::scene::*;
+
+use glam::{f32::*, *};
+use rayon::prelude::*;
+
+// Progressive passes double the sample count, but never add more than this many at once, so
+// long renders still finish a pass every so often.
+const MAX_PASS_SAMPLES: u32 = 64;
+
+// How many samples per pixel the pass after one that reached `done` should aim for.
+pub fn next_pass_target(done: u32, max_samples: u32) -> u32 {
+  (done + done.clamp(1, MAX_PASS_SAMPLES)).min(max_samples)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderSettings {
+  pub max_depth: i32,
+  pub seed: u64,
+  // Every pixel gets at least min_samples. With a noise threshold, pixels stop once their
+  // relative error drops below it, and otherwise they keep going to max_samples.
+  pub min_samples: u32,
+  pub max_samples: u32,
+  pub noise_threshold: Option<f32>,
+  pub filter: PixelFilter,
+}
+
+pub struct Renderer<'a> {
+  pub scene: &'a Scene,
+  pub camera: &'a Camera,
+  pub settings: RenderSettings,
+}
+
+impl<'a> Renderer<'a> {
+  // Brings every pixel up to `target` samples, or until it's converged. Once `stop` says so,
+  // pixels that haven't been started yet are left as they are.
+  pub fn render_pass(&self, film: &mut Film, target: u32, stop: impl Fn() -> bool + Sync, on_pixel: impl Fn() + Sync) {
+    let film_size = UVec2::new(film.width, film.height);
+    let splats = &film.splats;
+    film.pixels.par_iter_mut().enumerate().for_each(|(pixel, stats)| {
+      if !stop() {
+        self.render_pixel(film_size, pixel as u32, stats, splats, target);
+      }
+      on_pixel();
+    });
+  }
+
+  // Whether every pixel has all the samples it's going to get.
+  pub fn finished(&self, film: &Film) -> bool {
+    film.pixels.iter().all(|p| self.converged(p))
+  }
+
+  pub fn converged(&self, stats: &PixelStats) -> bool {
+    stats.count >= self.settings.max_samples
+      || (stats.count >= self.settings.min_samples
+        && self.settings.noise_threshold.is_some_and(|t| stats.relative_error() < t))
+  }
+
+  // Adds samples to one pixel until it has `target` of them, or it's converged. Each sample is
+  // also splatted into every pixel within the filter's reach.
+  pub fn render_pixel(&self, film_size: UVec2, pixel: u32, stats: &mut PixelStats, splats: &[Splat], target: u32) {
+    let xy = vec2((pixel % film_size.x) as f32, (pixel / film_size.x) as f32);
+    let mut ctx = TraceContext::new(self.settings.max_depth, self.settings.seed);
+    while stats.count < target && !self.converged(stats) {
+      ctx.start_sample(pixel as u64, stats.count as u64);
+      let position = xy + ctx.rng2();
+      let ray = self.camera.generate_ray(position / film_size.as_vec2(), &mut ctx);
+      let color = trace_path(self.scene, ray, &mut ctx);
+      stats.add(color);
+      if color.is_finite() {
+        self.splat(film_size, splats, position, color);
+      }
+    }
+  }
+
+  // `position` is in pixels, with pixel centers at half-integers.
+  fn splat(&self, film_size: UVec2, splats: &[Splat], position: Vec2, color: Vec3A) {
+    let radius = self.settings.filter.radius;
+    let min = (position - 0.5 - radius).ceil().max(Vec2::ZERO).as_uvec2();
+    let max = (position - 0.5 + radius).floor().as_ivec2().min(film_size.as_ivec2() - 1);
+    for y in min.y as i32..=max.y {
+      for x in min.x as i32..=max.x {
+        let weight = self.settings.filter.evaluate(vec2(x as f32, y as f32) + 0.5 - position);
+        if weight != 0. {
+          splats[(y as u32 * film_size.x + x as u32) as usize].add(color, weight);
+        }
+      }
+    }
+  }
+
+  // First-hit AOVs, averaged over `samples` rays per pixel. The rays are the same ones the
+  // first `samples` beauty samples start with, so the two line up exactly.
+  pub fn render_aovs(&self, width: u32, height: u32, samples: u32) -> AovBuffers {
+    let film_size = UVec2::new(width, height);
+    let pixels = (0..width * height).into_par_iter().map(|pixel| self.aov_pixel(film_size, pixel, samples)).collect();
+    AovBuffers { width, height, pixels }
+  }
+
+  fn aov_pixel(&self, film_size: UVec2, pixel: u32, samples: u32) -> AovPixel {
+    let xy = vec2((pixel % film_size.x) as f32, (pixel / film_size.x) as f32);
+    let mut ctx = TraceContext::new(self.settings.max_depth, self.settings.seed);
+    let mut aov = AovPixel::default();
+    let (mut albedo, mut normal, mut depth, mut position, mut hits) = (Vec3A::ZERO, Vec3A::ZERO, 0., Vec3A::ZERO, 0);
+    for sample in 0..samples {
+      ctx.start_sample(pixel as u64, sample as u64);
+      let film = (xy + ctx.rng2()) / film_size.as_vec2();
+      let ray = self.camera.generate_ray(film, &mut ctx);
+      if let Some((shape, hit)) = self.scene.closest_shape_hit(ray) {
+        albedo += hit.material.albedo(&hit);
+        normal += hit.world_normal;
+        depth += hit.distance;
+        position += hit.world_pos;
+        hits += 1;
+        aov.object_id.get_or_insert(shape as u32);
+      }
+    }
+    if hits > 0 {
+      let n = hits as f32;
+      aov.albedo = albedo / n;
+      aov.normal = normal.normalize_or_zero();
+      aov.depth = depth / n;
+      aov.position = position / n;
+    }
+    aov
+  }
+}
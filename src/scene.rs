@@ -1,4 +1,7 @@
 
+use crate::bvh::*;
+use crate::environment::*;
+use crate::lights::*;
 use crate::materials::*;
 use crate::shapes::*;
 use crate::geom::*;
@@ -12,31 +15,167 @@ use lerp::Lerp;
 use quasirandom::*;
 use rand::{Rng, rngs::ThreadRng, thread_rng};
 
-pub struct Scene<'a> {
-  pub shapes: Vec<Box<dyn 'a + Shape>>
+#[derive(Debug, Clone)]
+pub struct Scene {
+  shapes: Vec<Box<dyn Shape>>,
+  // Shapes with bounds live in the BVH, whose items index into `bounded`. The rest (like
+  // planes) get tested every time.
+  bvh: Bvh,
+  bounded: Vec<usize>,
+  unbounded: Vec<usize>,
+  // Shapes that get sampled for direct lighting, in increasing order.
+  lights: Vec<usize>,
+  environment: Box<dyn Environment>,
+  punctual_lights: Vec<PunctualLight>,
 }
 
-impl<'a> Scene<'a> {
-  pub fn get_color(&self, ray: Ray, ctx: &mut TraceContext) -> Vec3A {
-      let mut best_hit : Option<Hit> = None;
-      for shape in &self.shapes {
-          if let Some(hit) = shape.trace_ray(ray) {
-              if !hit.started_inside && hit.distance > 0.0001 &&
-                  (best_hit.is_none() || hit.distance < best_hit.unwrap().distance) {
-                  best_hit = Some(hit);
+// Light arriving at a point from a sampled light, with the solid angle pdf of having picked
+// that direction.
+#[derive(Debug, Clone, Copy)]
+pub struct LightSample {
+  pub direction: Vec3A,
+  pub radiance: Vec3A,
+  pub pdf: f32,
+}
+
+impl Scene {
+  pub fn new(
+    shapes: Vec<Box<dyn Shape>>,
+    environment: Box<dyn Environment>,
+    punctual_lights: Vec<PunctualLight>,
+  ) -> Scene {
+    let mut bounded = Vec::new();
+    let mut bounds = Vec::new();
+    let mut unbounded = Vec::new();
+    for (i, shape) in shapes.iter().enumerate() {
+      match shape.get_bounds() {
+        Some(b) => {
+          bounded.push(i);
+          bounds.push(b);
+        }
+        None => unbounded.push(i),
+      }
+    }
+
+    let lights = (0..shapes.len()).filter(|&i| shapes[i].is_light()).collect();
+    Scene { shapes, bvh: Bvh::new(&bounds), bounded, unbounded, lights, environment, punctual_lights }
+  }
+
+  pub fn shapes(&self) -> &[Box<dyn Shape>] {
+    &self.shapes
+  }
+
+  pub fn punctual_lights(&self) -> &[PunctualLight] {
+    &self.punctual_lights
+  }
+
+  // The closest surface the ray hits, if any. Shapes are only seen from the inside when their
+  // material lets light through, which is how rays get back out of glass.
+  pub fn closest_hit(&self, ray: Ray) -> Option<Hit<'_>> {
+      self.closest_shape_hit(ray).map(|(_, hit)| hit)
+  }
+
+  // Same as closest_hit, along with the index of the shape that was hit.
+  pub fn closest_shape_hit(&self, ray: Ray) -> Option<(usize, Hit<'_>)> {
+      let accept = |i: usize| {
+          self.shapes[i]
+              .trace_ray(ray)
+              .filter(|hit| hit.distance > MIN_HIT_DISTANCE)
+              .filter(|hit| !hit.started_inside || hit.material.is_transmissive())
+              .map(|hit| (hit.distance, (i, hit)))
+      };
+
+      let mut best_hit = self.bvh.closest_hit(ray, |i| accept(self.bounded[i as usize]));
+      for &i in &self.unbounded {
+          if let Some((distance, hit)) = accept(i) {
+              if best_hit.is_none_or(|(best, _)| distance < best) {
+                  best_hit = Some((distance, hit));
               }
           }
       }
+      best_hit.map(|(_, hit)| hit)
+  }
 
-      if let Some(hit) = best_hit {
-          let color = hit.material.get_color(self, ray, &hit, ctx);
-          color
+  // How many things sample_light picks between: the light shapes, and the environment when it
+  // wants to be sampled.
+  fn light_count(&self) -> usize {
+      self.lights.len() + self.environment.is_light() as usize
+  }
+
+  // Picks a light and a point on it, and returns the light arriving at the hit from there, or
+  // None if there are no lights. The radiance is zero when something's in the way.
+  pub fn sample_light(&self, hit: &Hit, ctx: &mut TraceContext) -> Option<LightSample> {
+      let count = self.light_count();
+      if count == 0 {
+          return None;
+      }
+      let pick = ((ctx.rng1() * count as f32) as usize).min(count - 1);
+      let u = ctx.rng2();
+
+      if pick == self.lights.len() {
+          let sample = self.environment.sample(u)?;
+          // Nothing can be in the way of the environment.
+          let radiance = match self.closest_hit(hit.spawn_ray(sample.direction)) {
+              Some(_) => Vec3A::ZERO,
+              None => sample.radiance,
+          };
+          return Some(LightSample { radiance, pdf: sample.pdf / count as f32, ..sample });
+      }
+
+      let light = self.lights[pick];
+      let sample = self.shapes[light].sample_surface(hit.world_pos, u)?;
+      let pdf = sample.pdf / count as f32;
+
+      let to_light = sample.position - hit.world_pos;
+      let dist = to_light.length();
+      if !(pdf > 0. && dist > MIN_HIT_DISTANCE) {
+          return None;
+      }
+      let direction = to_light / dist;
+
+      // Only count it if the first thing the shadow ray hits is the point we were aiming for.
+      let radiance = match self.closest_hit(hit.spawn_ray(direction)) {
+          Some(blocker) if (blocker.distance - dist).abs() <= 1e-3 * dist.max(1.) => {
+              blocker.material.emitted(&blocker, -direction)
+          }
+          _ => Vec3A::ZERO,
+      };
+      Some(LightSample { direction, radiance, pdf })
+  }
+
+  // The light reaching the hit from one of the punctual lights, with a pdf of 1 since there was
+  // only ever one direction to pick. The radiance is zero when something's in the way.
+  pub fn punctual_light(&self, light: &PunctualLight, hit: &Hit) -> Option<LightSample> {
+      let incident = light.incident(hit.world_pos)?;
+      let blocked = self
+          .closest_hit(hit.spawn_ray(incident.direction))
+          .is_some_and(|blocker| blocker.distance < incident.distance);
+      let radiance = if blocked { Vec3A::ZERO } else { incident.radiance };
+      Some(LightSample { direction: incident.direction, radiance, pdf: 1. })
+  }
+
+  // The pdf sample_light would have had for reaching `hit` on shape `shape` from `from`.
+  pub fn light_pdf(&self, shape: usize, from: Vec3A, hit: &Hit) -> f32 {
+      if self.lights.binary_search(&shape).is_ok() {
+          self.shapes[shape].surface_pdf(from, hit) / self.light_count() as f32
       } else {
-          //Vec3A::ZERO
-          0.05 * Vec3A::new(0.3, 0.2, 0.2)
-          //0.05 * Vec3A::new(0.1, 0.2, 0.3) + 0.002 * ray.direction.dot(Vec3A::new(-0.8, 1.2, 1.6).normalize()).max(0.).powf(10.) * Vec3A::new(200., 175., 150.)
+          0.
       }
   }
+
+  // The pdf sample_light would have had for picking `direction` towards the environment.
+  pub fn environment_pdf(&self, direction: Vec3A) -> f32 {
+      if self.environment.is_light() {
+          self.environment.pdf(direction) / self.light_count() as f32
+      } else {
+          0.
+      }
+  }
+
+  // What a ray sees when it doesn't hit anything.
+  pub fn background(&self, ray: Ray) -> Vec3A {
+      self.environment.radiance(ray.direction)
+  }
 }
 
 
@@ -47,111 +186,112 @@ pub struct RngSet<T : Quasirandom + FromUniform> {
 
 
 
+// SplitMix64's output function, used to scramble a pair of numbers into a new seed.
+fn mix(a: u64, b: u64) -> u64 {
+  let mut z = (a ^ b.rotate_left(32)).wrapping_add(0x9e37_79b9_7f4a_7c15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  z ^ (z >> 31)
+}
+
+// A hash as a number in 0..1.
+fn unit(h: u64) -> f64 {
+  (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Martin Roberts' R1, R2 and R3 sequences step by these, which are powers of the inverse of the
+// root of x^(d+1) = x + 1. The same numbers the quasirandom crate uses.
+const R1: [f64; 1] = [0.6180339887498955];
+const R2: [f64; 2] = [0.7548776662466942, 0.5698402909980553];
+const R3: [f64; 3] = [0.8191725133961674, 0.6710436067037939, 0.5497004779019761];
+
+// Random numbers for tracing one sample. Each call to rng1, rng2 or rng3 within a sample reads
+// the next of its own quasirandom sequences, and each sample reads one step further along all
+// of them, so a pixel's samples cover the space evenly. Everything is worked out from the seed,
+// the pixel and the sample, so a render comes out exactly the same however it's split between
+// threads.
 pub struct TraceContext {
-  current_depth: i32,
   max_depth: i32,
+  seed: u64,
+  // Picks a random starting point for each of this pixel's sequences.
+  pixel_hash: u64,
+  sequence_index: f64,
 
   next_rng1: usize,
   next_rng2: usize,
   next_rng3: usize,
-  rng1_list: Vec<Qrng<f32>>,
-  rng2_list: Vec<Qrng<(f32, f32)>>,
-  rng3_list: Vec<Qrng<(f32, f32, f32)>>,
-  thread_rng: ThreadRng,
-  reseed: f64,
+  // rngen's numbers are this hashed with how many it's handed out so far in the sample.
+  sample_hash: u64,
+  next_rngen: u64,
 }
 
 impl TraceContext {
-  pub fn new(max_depth: i32) -> TraceContext {
-    TraceContext {
-      current_depth: 0,
+  pub fn new(max_depth: i32, seed: u64) -> TraceContext {
+    let mut ctx = TraceContext {
       max_depth,
+      seed,
+      pixel_hash: 0,
+      sequence_index: 0.,
       next_rng1: 0,
       next_rng2: 0,
       next_rng3: 0,
-      rng1_list: Vec::new(),
-      rng2_list: Vec::new(),
-      rng3_list: Vec::new(),
-      thread_rng: thread_rng(),
-      reseed: thread_rng().gen(),
-    }
+      sample_hash: 0,
+      next_rngen: 0,
+    };
+    ctx.start_sample(0, 0);
+    ctx
   }
 
-  #[inline(always)]
-  pub fn try_push(&mut self) -> bool {
-    assert!(self.current_depth >= 0);
-    assert!(self.current_depth <= self.max_depth);
-    if self.current_depth == self.max_depth {
-      false
-    } else {
-      self.current_depth += 1;
-      true
-    }
+  // How many times a path is allowed to bounce.
+  pub fn max_depth(&self) -> i32 {
+    self.max_depth
   }
 
-  #[inline(always)]
-  pub fn pop(&mut self) {
-    assert!(self.current_depth > 0);
-    assert!(self.current_depth <= self.max_depth);
-    self.current_depth -= 1;
+  // Gets ready to trace sample number `sample` of pixel number `pixel`.
+  pub fn start_sample(&mut self, pixel: u64, sample: u64) {
+    self.pixel_hash = mix(self.seed, pixel);
+    self.sequence_index = (sample + 1) as f64;
+    self.next_rng1 = 0;
+    self.next_rng2 = 0;
+    self.next_rng3 = 0;
+    self.sample_hash = mix(self.pixel_hash, sample);
+    self.next_rngen = 0;
   }
 
+  // The current step of sequence number `dimension` of the ones with N components, each
+  // component shifted by its own random offset (a Cranley-Patterson rotation).
+  fn quasirandom<const N: usize>(&self, dimension: usize, steps: [f64; N]) -> [f32; N] {
+    let start = mix(self.pixel_hash, ((N as u64) << 32) | dimension as u64);
+    std::array::from_fn(|i| {
+      let offset = unit(mix(start, i as u64));
+      ((offset + self.sequence_index * steps[i]).fract() as f32).min(1. - f32::EPSILON / 2.)
+    })
+  }
 
+  // Plain pseudorandom numbers, for when there's no telling how many will be needed.
   #[inline(always)]
   pub fn rngen(&mut self) -> f32 {
-    self.thread_rng.gen()
+    self.next_rngen += 1;
+    (unit(mix(self.sample_hash, self.next_rngen - 1)) as f32).min(1. - f32::EPSILON / 2.)
   }
 
 
   #[inline(always)]
   pub fn rng1(&mut self) -> f32 {
-    if self.next_rng1 >= self.rng1_list.len() {
-      self.next_rng1 = self.rng1_list.len();
-      let seed = ((self.next_rng1 + 1) as f64 + self.reseed).exp().fract();
-      self.rng1_list.push(Qrng::<f32>::new(seed));
-    }
     self.next_rng1 += 1;
-    self.rng1_list[self.next_rng1 - 1].gen()
+    self.quasirandom(self.next_rng1 - 1, R1)[0]
   }
 
   #[inline(always)]
   pub fn rng2(&mut self) -> Vec2 {
-    if self.next_rng2 >= self.rng2_list.len() {
-      self.next_rng2 = self.rng2_list.len();
-      let seed = ((self.next_rng2 + 1) as f64 + self.reseed).exp().fract();
-      self.rng2_list.push(Qrng::<(f32, f32)>::new(seed));
-    }
     self.next_rng2 += 1;
-    Vec2::from(self.rng2_list[self.next_rng2 - 1].gen())
+    Vec2::from(self.quasirandom(self.next_rng2 - 1, R2))
   }
 
   #[inline(always)]
   pub fn rng3(&mut self) -> Vec3A {
-    if self.next_rng3 >= self.rng3_list.len() {
-      self.next_rng3 = self.rng3_list.len();
-      let seed = ((self.next_rng3 + 1) as f64 + self.reseed).exp().fract();
-      self.rng3_list.push(Qrng::<(f32, f32, f32)>::new(seed));
-    }
     self.next_rng3 += 1;
-    Vec3A::from(self.rng3_list[self.next_rng3 - 1].gen())
-  }
-
-  pub fn next_sample(&mut self) {
-    assert_eq!(self.current_depth, 0);
-    self.next_rng1 = 0;
-    self.next_rng2 = 0;
-    self.next_rng3 = 0;
-  }
-
-  pub fn next_pixel(&mut self) {
-    assert_eq!(self.current_depth, 0);
-    self.next_rng1 = 0;
-    self.next_rng2 = 0;
-    self.next_rng3 = 0;
-    self.rng1_list.clear();
-    self.rng2_list.clear();
-    self.rng3_list.clear();
-    self.reseed = self.thread_rng.gen();
+    Vec3A::from(self.quasirandom(self.next_rng3 - 1, R3))
   }
 
   #[inline(always)]
@@ -0,0 +1,1104 @@
+use crate::camera::*;
+use crate::checkpoint::*;
+use crate::environment::*;
+use crate::geom::*;
+use crate::lights::*;
+use crate::materials::*;
+use crate::mesh::*;
+use crate::primitives::*;
+use crate::scene::*;
+use crate::sdf::*;
+use crate::shapes::*;
+use crate::texture::*;
+
+use glam::{f32::*, *};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+// A scene description as it appears on disk. Materials are named, and shapes refer to them by
+// name. Vectors are plain lists so we can complain about the entry that has the wrong number of
+// components, instead of getting a generic "invalid length" from whichever parser we're using.
+
+#[derive(Debug, Deserialize)]
+pub struct SceneDesc {
+  pub camera: CameraDesc,
+  // How the image gets mapped for 8-bit output. Anything left out keeps its default.
+  #[serde(default)]
+  pub tone_map: ToneMap,
+  // What rays that miss everything see.
+  #[serde(default = "default_environment")]
+  pub environment: EnvironmentDesc,
+  pub materials: BTreeMap<String, MaterialDesc>,
+  pub shapes: Vec<ShapeDesc>,
+  // Shapes that aren't in the scene themselves, but can be placed in it any number of times
+  // with Instance. Each is only built once, however many times it's used.
+  #[serde(default)]
+  pub objects: BTreeMap<String, ShapeDesc>,
+  #[serde(default)]
+  pub lights: Vec<LightDesc>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CameraDesc {
+  pub position: Vec<f32>,
+  pub target: Vec<f32>,
+  #[serde(default = "default_up")]
+  pub up: Vec<f32>,
+  #[serde(default = "default_v_fov")]
+  pub v_fov: f32,
+  // Zero for a pinhole camera, where everything is in focus.
+  #[serde(default)]
+  pub aperture_radius: f32,
+  // Measured along the view direction. Defaults to the distance to `target`.
+  #[serde(default)]
+  pub focus_distance: Option<f32>,
+  #[serde(default)]
+  pub bokeh: ApertureShape,
+}
+
+fn default_up() -> Vec<f32> {
+  vec![0., 0., 1.]
+}
+
+fn default_v_fov() -> f32 {
+  45.
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum EnvironmentDesc {
+  Constant {
+    color: Vec<f32>,
+  },
+  Map {
+    // An equirectangular image, usually .hdr or .exr, relative to the scene file.
+    file: String,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    #[serde(default = "default_intensity")]
+    intensity: f32,
+  },
+  // A daylight sky with a sun in it.
+  Sky {
+    // Degrees above the horizon.
+    elevation: f32,
+    // Degrees from +X towards +Y.
+    azimuth: f32,
+    #[serde(default = "default_turbidity")]
+    turbidity: f32,
+    // Angular radius of the sun's disk, in degrees.
+    #[serde(default = "default_sun_radius")]
+    sun_radius: f32,
+    #[serde(default = "default_intensity")]
+    intensity: f32,
+  },
+}
+
+fn default_environment() -> EnvironmentDesc {
+  EnvironmentDesc::Constant { color: vec![0.015, 0.01, 0.01] }
+}
+
+fn default_intensity() -> f32 {
+  1.
+}
+
+fn default_turbidity() -> f32 {
+  3.
+}
+
+fn default_sun_radius() -> f32 {
+  0.27
+}
+
+// Colors and some numbers in materials can be textures instead. Plain values work as before,
+// and anything else is one of the named textures.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(
+  untagged,
+  expecting = "a number, a color like [1, 0.5, 0], or one of the textures: Image, Checker, Noise or Mix"
+)]
+pub enum TextureDesc {
+  // Gray when used for a color.
+  Value(f32),
+  Color(Vec<f32>),
+  Texture(Box<TextureKind>),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum TextureKind {
+  Image(ImageTextureDesc),
+  // Cubes `size` across in the shape's local space.
+  Checker {
+    size: f32,
+    a: TextureDesc,
+    b: TextureDesc,
+  },
+  // Fractal noise blending from `a` to `b`, with features about 1 / `frequency` across.
+  Noise {
+    frequency: f32,
+    #[serde(default = "default_octaves")]
+    octaves: u32,
+    a: TextureDesc,
+    b: TextureDesc,
+  },
+  // `a` blended toward `b` by `amount`, which is usually a texture itself.
+  Mix {
+    a: TextureDesc,
+    b: TextureDesc,
+    amount: TextureDesc,
+  },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageTextureDesc {
+  // Relative paths are relative to the scene file, not the working directory.
+  pub file: String,
+  // Give both of these to project the image flat across the shape: local position dotted
+  // with each gives the texture coordinate, in whole-image units. Otherwise the shape's own
+  // texture coordinates are used, times `uv_scale`.
+  #[serde(default)]
+  pub u_axis: Option<Vec<f32>>,
+  #[serde(default)]
+  pub v_axis: Option<Vec<f32>>,
+  #[serde(default = "default_uv_scale")]
+  pub uv_scale: Vec<f32>,
+  #[serde(default = "default_uv_offset")]
+  pub uv_offset: Vec<f32>,
+  // Takes the closest texel instead of blending the four around, for crisp pixel art.
+  #[serde(default)]
+  pub nearest: bool,
+  #[serde(default = "default_intensity")]
+  pub intensity: f32,
+}
+
+fn default_uv_scale() -> Vec<f32> {
+  vec![1., 1.]
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum MaterialDesc {
+  Lambertian {
+    color: TextureDesc,
+  },
+  GlossWrap {
+    gloss_color: TextureDesc,
+    diffuse_color: TextureDesc,
+    fresnel_power: f32,
+    gloss_size: TextureDesc,
+    max_gloss: f32,
+    min_gloss: f32,
+  },
+  Checkerboard {
+    size: f32,
+    a: String,
+    b: String,
+  },
+  BrushedMetal {
+    size: f32,
+    radial_roughness: f32,
+    circumference_roughness: f32,
+    color: TextureDesc,
+  },
+  Emitter {
+    color: TextureDesc,
+    #[serde(default)]
+    focus: f32,
+  },
+  Dielectric {
+    ior: f32,
+    #[serde(default)]
+    roughness: f32,
+    #[serde(default = "default_tint")]
+    tint: Vec<f32>,
+  },
+  // From before materials took textures. The same as a Lambertian with an Image projected
+  // across it, brightened 7 times.
+  TexturedLambert {
+    texture: String,
+    u_axis: Vec<f32>,
+    v_axis: Vec<f32>,
+    #[serde(default = "default_uv_offset")]
+    uv_offset: Vec<f32>,
+  },
+}
+
+fn default_tint() -> Vec<f32> {
+  vec![1., 1., 1.]
+}
+
+fn default_uv_offset() -> Vec<f32> {
+  vec![0., 0.]
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ShapeDesc {
+  Sphere {
+    center: Vec<f32>,
+    radius: f32,
+    material: String,
+  },
+  Plane {
+    normal: Vec<f32>,
+    right: Vec<f32>,
+    center: Vec<f32>,
+    material: String,
+  },
+  Cuboid {
+    origin: Vec<f32>,
+    // Euler angles in degrees, applied X first, then Y, then Z.
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    mins: Vec<f32>,
+    maxs: Vec<f32>,
+    material: String,
+  },
+  // The round shapes below stand on `origin` along their local +Z axis until rotated, with the
+  // same Euler angles as Cuboid.
+  Cylinder {
+    #[serde(default = "default_origin")]
+    origin: Vec<f32>,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    radius: f32,
+    height: f32,
+    material: String,
+  },
+  // Base at the origin, point at `height`.
+  Cone {
+    #[serde(default = "default_origin")]
+    origin: Vec<f32>,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    radius: f32,
+    height: f32,
+    material: String,
+  },
+  // Everything within `radius` of the line from the origin up to `length`.
+  Capsule {
+    #[serde(default = "default_origin")]
+    origin: Vec<f32>,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    radius: f32,
+    length: f32,
+    material: String,
+  },
+  // Lies flat around the origin, with the tube's center `major_radius` out from it.
+  Torus {
+    #[serde(default = "default_origin")]
+    origin: Vec<f32>,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    major_radius: f32,
+    minor_radius: f32,
+    material: String,
+  },
+  // Flat, facing +Z until rotated, and centered on the origin. Both sides are the same, and
+  // given an emissive material they're area lights.
+  Disk {
+    #[serde(default = "default_origin")]
+    origin: Vec<f32>,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    radius: f32,
+    material: String,
+  },
+  Rectangle {
+    #[serde(default = "default_origin")]
+    origin: Vec<f32>,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    // Along local X and Y.
+    size: Vec<f32>,
+    material: String,
+  },
+  Mesh {
+    // A Wavefront OBJ file, relative to the scene file.
+    file: String,
+    #[serde(default = "default_origin")]
+    origin: Vec<f32>,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    #[serde(default = "default_scale")]
+    scale: f32,
+    // Used for any part of the mesh whose OBJ material isn't in `material_map`.
+    material: String,
+    // OBJ (.mtl) material names to scene material names.
+    #[serde(default)]
+    material_map: BTreeMap<String, String>,
+  },
+  // Constructive solid geometry, which only works with solid shapes: spheres, cuboids,
+  // cylinders, cones, capsules, tori, instances of those, and other combinations.
+  Union {
+    shapes: Vec<ShapeDesc>,
+  },
+  Intersection {
+    shapes: Vec<ShapeDesc>,
+  },
+  // `shape` with every one of `minus` cut out of it.
+  Difference {
+    shape: Box<ShapeDesc>,
+    minus: Vec<ShapeDesc>,
+  },
+  // A surface given by a signed distance function, for shapes with no formula for where a ray
+  // hits them.
+  Sdf {
+    node: SdfDesc,
+    material: String,
+  },
+  // One of the named `objects`, moved, rotated and scaled.
+  Instance {
+    object: String,
+    #[serde(default = "default_origin")]
+    origin: Vec<f32>,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    // Per axis, so it can stretch the object as well as resize it.
+    #[serde(default = "default_scale3")]
+    scale: Vec<f32>,
+  },
+}
+
+fn default_origin() -> Vec<f32> {
+  vec![0., 0., 0.]
+}
+
+fn default_rotation() -> Vec<f32> {
+  vec![0., 0., 0.]
+}
+
+fn default_scale() -> f32 {
+  1.
+}
+
+fn default_scale3() -> Vec<f32> {
+  vec![1., 1., 1.]
+}
+
+// The parts of an Sdf shape. The basic shapes are centered on the origin, with any axis along
+// Z, and get moved into place with Transform.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum SdfDesc {
+  Sphere {
+    radius: f32,
+  },
+  Box {
+    size: Vec<f32>,
+    #[serde(default)]
+    rounding: f32,
+  },
+  Torus {
+    major_radius: f32,
+    minor_radius: f32,
+  },
+  Cylinder {
+    radius: f32,
+    height: f32,
+  },
+  Capsule {
+    radius: f32,
+    length: f32,
+  },
+  Transform {
+    node: Box<SdfDesc>,
+    #[serde(default = "default_origin")]
+    origin: Vec<f32>,
+    #[serde(default = "default_rotation")]
+    rotation: Vec<f32>,
+    // The same on every axis, unlike Instance.
+    #[serde(default = "default_scale")]
+    scale: f32,
+  },
+  // `smoothness` blends the parts together over about that distance, instead of leaving a crease.
+  Union {
+    nodes: Vec<SdfDesc>,
+    #[serde(default)]
+    smoothness: f32,
+  },
+  Intersection {
+    nodes: Vec<SdfDesc>,
+    #[serde(default)]
+    smoothness: f32,
+  },
+  Difference {
+    node: Box<SdfDesc>,
+    minus: Vec<SdfDesc>,
+    #[serde(default)]
+    smoothness: f32,
+  },
+  // `count` copies along each axis, `spacing` apart, starting with the original.
+  Repeat {
+    node: Box<SdfDesc>,
+    spacing: Vec<f32>,
+    count: Vec<u32>,
+  },
+  // Turns around Z by this many degrees per unit of height.
+  Twist {
+    node: Box<SdfDesc>,
+    degrees: f32,
+  },
+  // Roughens the surface with noise that moves it up to `amplitude` in or out. Each octave adds
+  // finer detail.
+  Displace {
+    node: Box<SdfDesc>,
+    amplitude: f32,
+    frequency: f32,
+    #[serde(default = "default_octaves")]
+    octaves: u32,
+  },
+}
+
+fn default_octaves() -> u32 {
+  4
+}
+
+// Point, spot and directional lights. Their brightness is color times intensity.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum LightDesc {
+  Point {
+    position: Vec<f32>,
+    color: Vec<f32>,
+    #[serde(default = "default_intensity")]
+    intensity: f32,
+  },
+  Spot {
+    position: Vec<f32>,
+    target: Vec<f32>,
+    color: Vec<f32>,
+    #[serde(default = "default_intensity")]
+    intensity: f32,
+    // Half the cone's opening, in degrees.
+    angle: f32,
+    // How much of the cone, from the edge in, fades out. 0 is a hard edge.
+    #[serde(default = "default_spot_blend")]
+    blend: f32,
+  },
+  Directional {
+    // The way the light travels.
+    direction: Vec<f32>,
+    color: Vec<f32>,
+    #[serde(default = "default_intensity")]
+    intensity: f32,
+  },
+}
+
+fn default_spot_blend() -> f32 {
+  0.15
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+  Io(PathBuf, std::io::Error),
+  Parse(PathBuf, String),
+  UnknownFormat(PathBuf),
+  UnknownMaterial { entry: String, name: String },
+  MaterialCycle { entry: String },
+  UnknownObject { entry: String, name: String },
+  ObjectCycle { entry: String },
+  NotSolid { entry: String },
+  EmptyCsg { entry: String },
+  BadVector { entry: String, field: &'static str, expected: usize, got: usize },
+  NotPositive { entry: String, field: &'static str, value: f32 },
+  Texture { entry: String, path: PathBuf, error: image::ImageError },
+  HalfProjection { entry: String },
+  Mesh { entry: String, path: PathBuf, error: tobj::LoadError },
+}
+
+impl fmt::Display for SceneError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SceneError::Io(path, e) => write!(f, "couldn't read {}: {}", path.display(), e),
+      SceneError::Parse(path, e) => write!(f, "couldn't parse {}: {}", path.display(), e),
+      SceneError::UnknownFormat(path) => write!(
+        f,
+        "don't know how to read {}, expected a .ron, .json or .toml file",
+        path.display()
+      ),
+      SceneError::UnknownMaterial { entry, name } => {
+        write!(f, "{} refers to unknown material \"{}\"", entry, name)
+      }
+      SceneError::MaterialCycle { entry } => write!(f, "{} ends up containing itself", entry),
+      SceneError::UnknownObject { entry, name } => {
+        write!(f, "{} refers to unknown object \"{}\"", entry, name)
+      }
+      SceneError::ObjectCycle { entry } => write!(f, "{} ends up containing itself", entry),
+      SceneError::NotSolid { entry } => write!(f, "{} isn't a solid shape, so it can't be used in CSG", entry),
+      SceneError::EmptyCsg { entry } => write!(f, "{} needs at least one shape", entry),
+      SceneError::BadVector { entry, field, expected, got } => write!(
+        f,
+        "{}: `{}` should have {} components, but has {}",
+        entry, field, expected, got
+      ),
+      SceneError::NotPositive { entry, field, value } => {
+        write!(f, "{}: `{}` should be more than zero, but is {}", entry, field, value)
+      }
+      SceneError::Texture { entry, path, error } => {
+        write!(f, "{}: couldn't load texture {}: {}", entry, path.display(), error)
+      }
+      SceneError::HalfProjection { entry } => write!(f, "{}: an image texture needs both u_axis and v_axis, or neither", entry),
+      SceneError::Mesh { entry, path, error } => {
+        write!(f, "{}: couldn't load mesh {}: {}", entry, path.display(), error)
+      }
+    }
+  }
+}
+
+impl std::error::Error for SceneError {}
+
+pub struct CameraSettings {
+  pub position: Vec3A,
+  pub target: Vec3A,
+  pub up: Vec3A,
+  pub v_fov: f32,
+  pub lens: Option<ThinLens>,
+}
+
+impl CameraSettings {
+  // The image's shape isn't part of the scene, so the camera gets made once we know it.
+  pub fn camera(&self, aspect: f32) -> Camera {
+    let camera = Camera::new(self.position, self.target, self.up, self.v_fov, aspect);
+    match self.lens {
+      Some(lens) => camera.with_lens(lens),
+      None => camera,
+    }
+  }
+}
+
+pub struct LoadedScene {
+  pub scene: Scene,
+  pub camera: CameraSettings,
+  pub tone_map: ToneMap,
+  // Changes whenever anything that goes into the rendered image does: the description, apart
+  // from the tone mapping, and every file it loads.
+  pub hash: u64,
+}
+
+pub fn load_scene(path: &Path) -> Result<LoadedScene, SceneError> {
+  let text = std::fs::read_to_string(path).map_err(|e| SceneError::Io(path.to_owned(), e))?;
+  let parse_error = |e: &dyn fmt::Display| SceneError::Parse(path.to_owned(), e.to_string());
+  let desc: SceneDesc = match path.extension().and_then(|e| e.to_str()) {
+    Some("ron") => ron::from_str(&text).map_err(|e| parse_error(&e))?,
+    Some("json") => serde_json::from_str(&text).map_err(|e| parse_error(&e))?,
+    Some("toml") => toml::from_str(&text).map_err(|e| parse_error(&e))?,
+    _ => return Err(SceneError::UnknownFormat(path.to_owned())),
+  };
+  desc.build(path.parent().unwrap_or_else(|| Path::new("")))
+}
+
+// Euler angles in degrees, applied X first, then Y, then Z.
+fn euler_degrees(angles: Vec3A) -> Quat {
+  Quat::from_euler(EulerRot::ZYX, angles.z.to_radians(), angles.y.to_radians(), angles.x.to_radians())
+}
+
+fn vec3_field(entry: &str, field: &'static str, v: &[f32]) -> Result<Vec3A, SceneError> {
+  match v {
+    &[x, y, z] => Ok(vec3a(x, y, z)),
+    _ => Err(SceneError::BadVector { entry: entry.to_owned(), field, expected: 3, got: v.len() }),
+  }
+}
+
+fn frame_fields(entry: &str, origin: &[f32], rotation: &[f32]) -> Result<Frame, SceneError> {
+  Ok(Frame::new(vec3_field(entry, "origin", origin)?, euler_degrees(vec3_field(entry, "rotation", rotation)?)))
+}
+
+// Sizes, which have to be more than zero for the shape to be there at all.
+fn positive_field(entry: &str, field: &'static str, v: f32) -> Result<f32, SceneError> {
+  if v > 0. {
+    Ok(v)
+  } else {
+    Err(SceneError::NotPositive { entry: entry.to_owned(), field, value: v })
+  }
+}
+
+fn vec2_field(entry: &str, field: &'static str, v: &[f32]) -> Result<Vec2, SceneError> {
+  match v {
+    &[x, y] => Ok(vec2(x, y)),
+    _ => Err(SceneError::BadVector { entry: entry.to_owned(), field, expected: 2, got: v.len() }),
+  }
+}
+
+struct MaterialBuilder<'d> {
+  descs: &'d BTreeMap<String, MaterialDesc>,
+  base_dir: &'d Path,
+  built: HashMap<String, Arc<dyn Material>>,
+  in_progress: Vec<String>,
+  // The contents of every file loaded so far, in order.
+  files: SceneHasher,
+}
+
+impl<'d> MaterialBuilder<'d> {
+  fn get(&mut self, name: &str, referenced_by: &str) -> Result<Arc<dyn Material>, SceneError> {
+    if let Some(m) = self.built.get(name) {
+      return Ok(m.clone());
+    }
+    let desc = self.descs.get(name).ok_or_else(|| SceneError::UnknownMaterial {
+      entry: referenced_by.to_owned(),
+      name: name.to_owned(),
+    })?;
+
+    let entry = format!("material \"{}\"", name);
+    if self.in_progress.iter().any(|n| n == name) {
+      return Err(SceneError::MaterialCycle { entry });
+    }
+    self.in_progress.push(name.to_owned());
+
+    let material: Arc<dyn Material> = match desc {
+      MaterialDesc::Lambertian { color } => {
+        Arc::new(Lambertian(self.texture(color, &entry, "color")?))
+      }
+      MaterialDesc::GlossWrap { gloss_color, diffuse_color, fresnel_power, gloss_size, max_gloss, min_gloss } => {
+        Arc::new(GlossWrap {
+          gloss_color: self.texture(gloss_color, &entry, "gloss_color")?,
+          diffuse_color: self.texture(diffuse_color, &entry, "diffuse_color")?,
+          fresnel_power: *fresnel_power,
+          gloss_size: self.texture(gloss_size, &entry, "gloss_size")?,
+          max_gloss: *max_gloss,
+          min_gloss: *min_gloss,
+        })
+      }
+      MaterialDesc::Checkerboard { size, a, b } => Arc::new(Checkerboard {
+        size: *size,
+        a: self.get(a, &entry)?,
+        b: self.get(b, &entry)?,
+      }),
+      MaterialDesc::BrushedMetal { size, radial_roughness, circumference_roughness, color } => {
+        Arc::new(BrushedMetal {
+          size: *size,
+          radial_roughness: *radial_roughness,
+          circumference_roughness: *circumference_roughness,
+          color: self.texture(color, &entry, "color")?,
+        })
+      }
+      MaterialDesc::Emitter { color, focus } => Arc::new(Emitter {
+        color: self.texture(color, &entry, "color")?,
+        focus: *focus,
+      }),
+      MaterialDesc::Dielectric { ior, roughness, tint } => Arc::new(Dielectric {
+        ior: *ior,
+        roughness: *roughness,
+        tint: vec3_field(&entry, "tint", tint)?,
+      }),
+      MaterialDesc::TexturedLambert { texture, u_axis, v_axis, uv_offset } => {
+        let image = ImageTextureDesc {
+          file: texture.clone(),
+          u_axis: Some(u_axis.clone()),
+          v_axis: Some(v_axis.clone()),
+          uv_scale: default_uv_scale(),
+          uv_offset: uv_offset.clone(),
+          nearest: false,
+          intensity: 7.,
+        };
+        Arc::new(Lambertian(Arc::new(self.image_texture(&image, &entry)?)))
+      }
+    };
+
+    self.in_progress.pop();
+    self.built.insert(name.to_owned(), material.clone());
+    Ok(material)
+  }
+
+  fn texture(&mut self, desc: &TextureDesc, entry: &str, field: &'static str) -> Result<Arc<dyn Texture>, SceneError> {
+    let kind = match desc {
+      TextureDesc::Value(v) => return Ok(Arc::new(ConstantTexture(Vec3A::splat(*v)))),
+      TextureDesc::Color(color) => return Ok(Arc::new(ConstantTexture(vec3_field(entry, field, color)?))),
+      TextureDesc::Texture(kind) => kind.as_ref(),
+    };
+    Ok(match kind {
+      TextureKind::Image(image) => Arc::new(self.image_texture(image, entry)?),
+      TextureKind::Checker { size, a, b } => Arc::new(CheckerTexture {
+        size: *size,
+        a: self.texture(a, entry, field)?,
+        b: self.texture(b, entry, field)?,
+      }),
+      TextureKind::Noise { frequency, octaves, a, b } => Arc::new(NoiseTexture {
+        frequency: *frequency,
+        octaves: *octaves,
+        a: self.texture(a, entry, field)?,
+        b: self.texture(b, entry, field)?,
+      }),
+      TextureKind::Mix { a, b, amount } => Arc::new(MixTexture {
+        a: self.texture(a, entry, field)?,
+        b: self.texture(b, entry, field)?,
+        amount: self.texture(amount, entry, "amount")?,
+      }),
+    })
+  }
+
+  fn image_texture(&mut self, desc: &ImageTextureDesc, entry: &str) -> Result<ImageTexture, SceneError> {
+    let ImageTextureDesc { file, u_axis, v_axis, uv_scale, uv_offset, nearest, intensity } = desc;
+    let offset = vec2_field(entry, "uv_offset", uv_offset)?;
+    let projection = match (u_axis, v_axis) {
+      (Some(u), Some(v)) => {
+        let (u, v) = (vec3_field(entry, "u_axis", u)?, vec3_field(entry, "v_axis", v)?);
+        Projection::Planar {
+          local_to_uv: Affine3A::from_cols(
+            vec3a(u.x, v.x, 0.),
+            vec3a(u.y, v.y, 0.),
+            vec3a(u.z, v.z, 0.),
+            vec3a(offset.x, offset.y, 0.),
+          ),
+        }
+      }
+      (None, None) => Projection::Uv { scale: vec2_field(entry, "uv_scale", uv_scale)?, offset },
+      _ => return Err(SceneError::HalfProjection { entry: entry.to_owned() }),
+    };
+    let path = self.base_dir.join(file);
+    let texture_error = |error| SceneError::Texture { entry: entry.to_owned(), path: path.clone(), error };
+    let bytes = std::fs::read(&path).map_err(|e| texture_error(image::ImageError::IoError(e)))?;
+    let image = load_linear_image(&path, &bytes).map_err(texture_error)?;
+    self.files.write(&bytes);
+    let filter = if *nearest { TextureFilter::Nearest } else { TextureFilter::Bilinear };
+    Ok(ImageTexture::new(&image, projection, filter, *intensity))
+  }
+}
+
+struct ObjectBuilder<'d> {
+  descs: &'d BTreeMap<String, ShapeDesc>,
+  base_dir: &'d Path,
+  built: HashMap<String, Arc<dyn Shape>>,
+  in_progress: Vec<String>,
+  files: SceneHasher,
+}
+
+impl<'d> ObjectBuilder<'d> {
+  fn get(&mut self, name: &str, referenced_by: &str, materials: &mut MaterialBuilder) -> Result<Arc<dyn Shape>, SceneError> {
+    if let Some(object) = self.built.get(name) {
+      return Ok(object.clone());
+    }
+    let desc = self.descs.get(name).ok_or_else(|| SceneError::UnknownObject {
+      entry: referenced_by.to_owned(),
+      name: name.to_owned(),
+    })?;
+
+    let entry = format!("object \"{}\"", name);
+    if self.in_progress.iter().any(|n| n == name) {
+      return Err(SceneError::ObjectCycle { entry });
+    }
+    self.in_progress.push(name.to_owned());
+    let object: Arc<dyn Shape> = self.build(desc, &entry, materials)?.into();
+    self.in_progress.pop();
+    self.built.insert(name.to_owned(), object.clone());
+    Ok(object)
+  }
+
+  fn build(&mut self, desc: &ShapeDesc, entry: &str, materials: &mut MaterialBuilder) -> Result<Box<dyn Shape>, SceneError> {
+    let shape: Box<dyn Shape> = match desc {
+      ShapeDesc::Sphere { center, radius, material } => {
+        Box::new(Sphere {
+          center: vec3_field(entry, "center", center)?,
+          radius: *radius,
+          material: materials.get(material, entry)?,
+        })
+      }
+      ShapeDesc::Plane { normal, right, center, material } => {
+        Box::new(Plane::new(
+          vec3_field(entry, "normal", normal)?,
+          vec3_field(entry, "right", right)?,
+          vec3_field(entry, "center", center)?,
+          materials.get(material, entry)?,
+        ))
+      }
+      ShapeDesc::Cuboid { origin, rotation, mins, maxs, material } => {
+        Box::new(Cuboid::new(
+          vec3_field(entry, "origin", origin)?,
+          euler_degrees(vec3_field(entry, "rotation", rotation)?),
+          vec3_field(entry, "mins", mins)?,
+          vec3_field(entry, "maxs", maxs)?,
+          materials.get(material, entry)?,
+        ))
+      }
+      ShapeDesc::Cylinder { origin, rotation, radius, height, material } => Box::new(Cylinder {
+        frame: frame_fields(entry, origin, rotation)?,
+        radius: positive_field(entry, "radius", *radius)?,
+        height: positive_field(entry, "height", *height)?,
+        material: materials.get(material, entry)?,
+      }),
+      ShapeDesc::Cone { origin, rotation, radius, height, material } => Box::new(Cone {
+        frame: frame_fields(entry, origin, rotation)?,
+        radius: positive_field(entry, "radius", *radius)?,
+        height: positive_field(entry, "height", *height)?,
+        material: materials.get(material, entry)?,
+      }),
+      ShapeDesc::Capsule { origin, rotation, radius, length, material } => Box::new(Capsule {
+        frame: frame_fields(entry, origin, rotation)?,
+        radius: positive_field(entry, "radius", *radius)?,
+        length: positive_field(entry, "length", *length)?,
+        material: materials.get(material, entry)?,
+      }),
+      ShapeDesc::Torus { origin, rotation, major_radius, minor_radius, material } => Box::new(Torus {
+        frame: frame_fields(entry, origin, rotation)?,
+        major_radius: positive_field(entry, "major_radius", *major_radius)?,
+        minor_radius: positive_field(entry, "minor_radius", *minor_radius)?,
+        material: materials.get(material, entry)?,
+      }),
+      ShapeDesc::Disk { origin, rotation, radius, material } => Box::new(Disk {
+        frame: frame_fields(entry, origin, rotation)?,
+        radius: positive_field(entry, "radius", *radius)?,
+        material: materials.get(material, entry)?,
+      }),
+      ShapeDesc::Rectangle { origin, rotation, size, material } => Box::new(Rectangle {
+        frame: frame_fields(entry, origin, rotation)?,
+        size: {
+          let size = vec2_field(entry, "size", size)?;
+          vec2(positive_field(entry, "size", size.x)?, positive_field(entry, "size", size.y)?)
+        },
+        material: materials.get(material, entry)?,
+      }),
+      ShapeDesc::Mesh { file, origin, rotation, scale, material, material_map } => {
+        let transform = Affine3A::from_scale_rotation_translation(
+          Vec3::splat(*scale),
+          euler_degrees(vec3_field(entry, "rotation", rotation)?),
+          vec3_field(entry, "origin", origin)?.into(),
+        );
+        let mut mapped = HashMap::new();
+        for (obj_name, scene_name) in material_map {
+          mapped.insert(obj_name.clone(), materials.get(scene_name, entry)?);
+        }
+        let path = self.base_dir.join(file);
+        let mesh_error = |error| SceneError::Mesh { entry: entry.to_owned(), path: path.clone(), error };
+        let bytes = std::fs::read(&path).map_err(|_| mesh_error(tobj::LoadError::OpenFileFailed))?;
+        let mesh = Mesh::load_obj(&path, &bytes, transform, materials.get(material, entry)?, &mapped)
+          .map_err(mesh_error)?;
+        self.files.write(&bytes);
+        Box::new(mesh)
+      }
+      ShapeDesc::Union { shapes } => self.build_csg(CsgOp::Union, None, shapes, entry, materials)?,
+      ShapeDesc::Intersection { shapes } => self.build_csg(CsgOp::Intersection, None, shapes, entry, materials)?,
+      ShapeDesc::Difference { shape, minus } => {
+        self.build_csg(CsgOp::Difference, Some(shape), minus, entry, materials)?
+      }
+      ShapeDesc::Sdf { node, material } => Box::new(Sdf::new(build_sdf(node, entry)?, materials.get(material, entry)?)),
+      ShapeDesc::Instance { object, origin, rotation, scale } => {
+        let local_to_world = Affine3A::from_scale_rotation_translation(
+          vec3_field(entry, "scale", scale)?.into(),
+          euler_degrees(vec3_field(entry, "rotation", rotation)?),
+          vec3_field(entry, "origin", origin)?.into(),
+        );
+        Box::new(Instance::new(self.get(object, entry, materials)?, local_to_world))
+      }
+    };
+    Ok(shape)
+  }
+
+  // Combines `first`, or else the first of `rest`, with each of the others in turn.
+  fn build_csg(
+    &mut self,
+    op: CsgOp,
+    first: Option<&ShapeDesc>,
+    rest: &[ShapeDesc],
+    entry: &str,
+    materials: &mut MaterialBuilder,
+  ) -> Result<Box<dyn Shape>, SceneError> {
+    let mut parts = Vec::with_capacity(rest.len() + 1);
+    for (i, desc) in first.into_iter().chain(rest).enumerate() {
+      let part_entry = format!("{}, part #{} ({})", entry, i, desc.kind());
+      let part = self.build(desc, &part_entry, materials)?;
+      if !part.is_solid() {
+        return Err(SceneError::NotSolid { entry: part_entry });
+      }
+      parts.push(part);
+    }
+    let mut parts = parts.into_iter();
+    let first = parts.next().ok_or_else(|| SceneError::EmptyCsg { entry: entry.to_owned() })?;
+    Ok(parts.fold(first, |combined, part| Box::new(Csg::new(op, combined, part))))
+  }
+}
+
+fn build_sdf(desc: &SdfDesc, entry: &str) -> Result<SdfNode, SceneError> {
+  // Folds a list of nodes together pairwise, the same way CSG shapes are.
+  let combine = |first: SdfNode, rest: &[SdfDesc], join: &dyn Fn(Box<SdfNode>, Box<SdfNode>) -> SdfNode| {
+    rest.iter().try_fold(first, |a, b| Ok(join(Box::new(a), Box::new(build_sdf(b, entry)?))))
+  };
+  let first = |nodes: &[SdfDesc]| match nodes.first() {
+    Some(node) => build_sdf(node, entry),
+    None => Err(SceneError::EmptyCsg { entry: entry.to_owned() }),
+  };
+  Ok(match desc {
+    SdfDesc::Sphere { radius } => SdfNode::Sphere { radius: positive_field(entry, "radius", *radius)? },
+    SdfDesc::Box { size, rounding } => {
+      let size = vec3_field(entry, "size", size)?;
+      for v in size.to_array() {
+        positive_field(entry, "size", v)?;
+      }
+      SdfNode::Box { half_size: 0.5 * size, rounding: *rounding }
+    }
+    SdfDesc::Torus { major_radius, minor_radius } => SdfNode::Torus {
+      major_radius: positive_field(entry, "major_radius", *major_radius)?,
+      minor_radius: positive_field(entry, "minor_radius", *minor_radius)?,
+    },
+    SdfDesc::Cylinder { radius, height } => SdfNode::Cylinder {
+      radius: positive_field(entry, "radius", *radius)?,
+      height: positive_field(entry, "height", *height)?,
+    },
+    SdfDesc::Capsule { radius, length } => SdfNode::Capsule {
+      radius: positive_field(entry, "radius", *radius)?,
+      length: positive_field(entry, "length", *length)?,
+    },
+    SdfDesc::Transform { node, origin, rotation, scale } => {
+      let to_world = Affine3A::from_rotation_translation(
+        euler_degrees(vec3_field(entry, "rotation", rotation)?),
+        vec3_field(entry, "origin", origin)?.into(),
+      );
+      let scale = positive_field(entry, "scale", *scale)?;
+      SdfNode::Transform { node: Box::new(build_sdf(node, entry)?), to_local: to_world.inverse(), scale }
+    }
+    SdfDesc::Union { nodes, smoothness } => {
+      combine(first(nodes)?, &nodes[1..], &|a, b| SdfNode::Union { a, b, smoothness: *smoothness })?
+    }
+    SdfDesc::Intersection { nodes, smoothness } => {
+      combine(first(nodes)?, &nodes[1..], &|a, b| SdfNode::Intersection { a, b, smoothness: *smoothness })?
+    }
+    SdfDesc::Difference { node, minus, smoothness } => {
+      combine(build_sdf(node, entry)?, minus, &|a, b| SdfNode::Difference { a, b, smoothness: *smoothness })?
+    }
+    SdfDesc::Repeat { node, spacing, count } => SdfNode::Repeat {
+      node: Box::new(build_sdf(node, entry)?),
+      spacing: vec3_field(entry, "spacing", spacing)?,
+      count: match count[..] {
+        [x, y, z] => uvec3(x, y, z),
+        _ => return Err(SceneError::BadVector { entry: entry.to_owned(), field: "count", expected: 3, got: count.len() }),
+      },
+    },
+    SdfDesc::Twist { node, degrees } => SdfNode::Twist { node: Box::new(build_sdf(node, entry)?), rate: degrees.to_radians() },
+    SdfDesc::Displace { node, amplitude, frequency, octaves } => SdfNode::Displace {
+      node: Box::new(build_sdf(node, entry)?),
+      amplitude: *amplitude,
+      frequency: *frequency,
+      octaves: *octaves,
+    },
+  })
+}
+
+impl ShapeDesc {
+  fn kind(&self) -> &'static str {
+    match self {
+      ShapeDesc::Sphere { .. } => "Sphere",
+      ShapeDesc::Plane { .. } => "Plane",
+      ShapeDesc::Cuboid { .. } => "Cuboid",
+      ShapeDesc::Cylinder { .. } => "Cylinder",
+      ShapeDesc::Cone { .. } => "Cone",
+      ShapeDesc::Capsule { .. } => "Capsule",
+      ShapeDesc::Torus { .. } => "Torus",
+      ShapeDesc::Disk { .. } => "Disk",
+      ShapeDesc::Rectangle { .. } => "Rectangle",
+      ShapeDesc::Mesh { .. } => "Mesh",
+      ShapeDesc::Union { .. } => "Union",
+      ShapeDesc::Intersection { .. } => "Intersection",
+      ShapeDesc::Difference { .. } => "Difference",
+      ShapeDesc::Sdf { .. } => "Sdf",
+      ShapeDesc::Instance { .. } => "Instance",
+    }
+  }
+}
+
+impl SceneDesc {
+  pub fn build(&self, base_dir: &Path) -> Result<LoadedScene, SceneError> {
+    let position = vec3_field("camera", "position", &self.camera.position)?;
+    let target = vec3_field("camera", "target", &self.camera.target)?;
+    let up = vec3_field("camera", "up", &self.camera.up)?;
+    let lens = (self.camera.aperture_radius > 0.).then(|| ThinLens {
+      aperture_radius: self.camera.aperture_radius,
+      focus_distance: self.camera.focus_distance.unwrap_or_else(|| (target - position).length()),
+      shape: self.camera.bokeh,
+    });
+    let camera = CameraSettings { position, target, up, v_fov: self.camera.v_fov.to_radians(), lens };
+
+    let mut materials = MaterialBuilder {
+      descs: &self.materials,
+      base_dir,
+      built: HashMap::new(),
+      in_progress: Vec::new(),
+      files: SceneHasher::new(),
+    };
+    for name in self.materials.keys() {
+      materials.get(name, "scene")?;
+    }
+
+    let mut objects = ObjectBuilder {
+      descs: &self.objects,
+      base_dir,
+      built: HashMap::new(),
+      in_progress: Vec::new(),
+      files: SceneHasher::new(),
+    };
+    let mut shapes: Vec<Box<dyn Shape>> = Vec::with_capacity(self.shapes.len());
+    for (i, desc) in self.shapes.iter().enumerate() {
+      let entry = format!("shape #{} ({})", i, desc.kind());
+      shapes.push(objects.build(desc, &entry, &mut materials)?);
+    }
+
+    let mut environment_file = SceneHasher::new();
+    let environment: Box<dyn Environment> = match &self.environment {
+      EnvironmentDesc::Constant { color } => {
+        Box::new(ConstantEnvironment(vec3_field("environment", "color", color)?))
+      }
+      EnvironmentDesc::Map { file, rotation, intensity } => {
+        let rotation = euler_degrees(vec3_field("environment", "rotation", rotation)?);
+        let path = base_dir.join(file);
+        let texture_error = |error| SceneError::Texture { entry: "environment".to_owned(), path: path.clone(), error };
+        let bytes = std::fs::read(&path).map_err(|e| texture_error(image::ImageError::IoError(e)))?;
+        let map = ImageEnvironment::load(&path, &bytes, rotation, *intensity).map_err(texture_error)?;
+        environment_file.write(&bytes);
+        Box::new(map)
+      }
+      EnvironmentDesc::Sky { elevation, azimuth, turbidity, sun_radius, intensity } => Box::new(
+        SkyEnvironment::new(*elevation, *azimuth, *turbidity, *sun_radius, *intensity),
+      ),
+    };
+
+    let mut punctual_lights = Vec::with_capacity(self.lights.len());
+    for (i, desc) in self.lights.iter().enumerate() {
+      let light = match desc {
+        LightDesc::Point { position, color, intensity } => {
+          let entry = format!("light #{} (Point)", i);
+          PunctualLight::Point {
+            position: vec3_field(&entry, "position", position)?,
+            intensity: *intensity * vec3_field(&entry, "color", color)?,
+          }
+        }
+        LightDesc::Spot { position, target, color, intensity, angle, blend } => {
+          let entry = format!("light #{} (Spot)", i);
+          let position = vec3_field(&entry, "position", position)?;
+          let outer = angle.to_radians();
+          PunctualLight::Spot {
+            position,
+            direction: (vec3_field(&entry, "target", target)? - position).normalize_or_zero(),
+            intensity: *intensity * vec3_field(&entry, "color", color)?,
+            cos_inner: (outer * (1. - blend.clamp(0., 1.))).cos(),
+            cos_outer: outer.cos(),
+          }
+        }
+        LightDesc::Directional { direction, color, intensity } => {
+          let entry = format!("light #{} (Directional)", i);
+          PunctualLight::Directional {
+            direction: vec3_field(&entry, "direction", direction)?.normalize_or_zero(),
+            irradiance: *intensity * vec3_field(&entry, "color", color)?,
+          }
+        }
+      };
+      punctual_lights.push(light);
+    }
+
+    // The description goes in as it was understood rather than as it was written, so comments and
+    // formatting don't count. JSON is just a fixed way of writing it out; maps are sorted, and
+    // numbers always come out the same.
+    let mut hasher = SceneHasher::new();
+    let description = (&self.camera, &self.environment, &self.materials, &self.shapes, &self.objects, &self.lights);
+    hasher.write(&serde_json::to_vec(&description).expect("scene descriptions always serialize"));
+    for files in [materials.files, objects.files, environment_file] {
+      hasher.write(&files.finish().to_le_bytes());
+    }
+
+    Ok(LoadedScene {
+      scene: Scene::new(shapes, environment, punctual_lights),
+      camera,
+      tone_map: self.tone_map,
+      hash: hasher.finish(),
+    })
+  }
+}
@@ -0,0 +1,287 @@
+use crate::geom::*;
+use crate::materials::*;
+use crate::noise::*;
+use crate::shapes::*;
+
+use glam::{f32::*, *};
+use std::sync::Arc;
+
+// Closer than this to the surface counts as a hit. It has to stay well under MIN_HIT_DISTANCE,
+// so a ray leaving the surface starts out clearly on one side of it.
+const EPSILON: f32 = 1e-5;
+const MAX_STEPS: u32 = 1000;
+
+// A tree of signed distance functions: negative inside, positive outside, and zero on the
+// surface. The primitives are all centered on the origin, and the ones with an axis have it
+// along Z.
+#[derive(Debug, Clone)]
+pub enum SdfNode {
+    Sphere { radius: f32 },
+    // Corners and edges are rounded off by `rounding` without changing the overall size.
+    Box { half_size: Vec3A, rounding: f32 },
+    // Lying flat, like the Torus shape.
+    Torus { major_radius: f32, minor_radius: f32 },
+    Cylinder { radius: f32, height: f32 },
+    // `length` is between the centers of the two round ends.
+    Capsule { radius: f32, length: f32 },
+    // Scaled by `scale`, then rotated and moved by the inverse of `to_local`. Scaling is the
+    // same on every axis, since stretching would stop distances being distances.
+    Transform { node: Box<SdfNode>, to_local: Affine3A, scale: f32 },
+    // With `smoothness` above zero, the seams are filled in with a fillet about that wide.
+    Union { a: Box<SdfNode>, b: Box<SdfNode>, smoothness: f32 },
+    Intersection { a: Box<SdfNode>, b: Box<SdfNode>, smoothness: f32 },
+    // `a` with `b` carved out of it.
+    Difference { a: Box<SdfNode>, b: Box<SdfNode>, smoothness: f32 },
+    // `count` copies along each axis, `spacing` apart, starting at the origin. Each copy only
+    // sees its own cell, so the node shouldn't reach more than half a spacing from its center.
+    Repeat { node: Box<SdfNode>, spacing: Vec3A, count: UVec3 },
+    // Twisted around Z by `rate` radians per unit of height.
+    Twist { node: Box<SdfNode>, rate: f32 },
+    // The surface pushed out and in by fractal noise.
+    Displace { node: Box<SdfNode>, amplitude: f32, frequency: f32, octaves: u32 },
+}
+
+impl SdfNode {
+    pub fn distance(&self, p: Vec3A) -> f32 {
+        match self {
+            SdfNode::Sphere { radius } => p.length() - radius,
+            SdfNode::Box { half_size, rounding } => {
+                let q = p.abs() - (*half_size - Vec3A::splat(*rounding));
+                q.max(Vec3A::ZERO).length() + q.max_element().min(0.) - rounding
+            }
+            SdfNode::Torus { major_radius, minor_radius } => {
+                vec2(p.truncate().length() - major_radius, p.z).length() - minor_radius
+            }
+            SdfNode::Cylinder { radius, height } => {
+                let d = vec2(p.truncate().length() - radius, p.z.abs() - 0.5 * height);
+                d.max_element().min(0.) + d.max(Vec2::ZERO).length()
+            }
+            SdfNode::Capsule { radius, length } => {
+                let half = 0.5 * length;
+                (p - vec3a(0., 0., p.z.clamp(-half, half))).length() - radius
+            }
+            SdfNode::Transform { node, to_local, scale } => {
+                scale * node.distance(to_local.transform_point3a(p) / *scale)
+            }
+            SdfNode::Union { a, b, smoothness } => smooth_min(a.distance(p), b.distance(p), *smoothness),
+            SdfNode::Intersection { a, b, smoothness } => -smooth_min(-a.distance(p), -b.distance(p), *smoothness),
+            SdfNode::Difference { a, b, smoothness } => -smooth_min(-a.distance(p), b.distance(p), *smoothness),
+            SdfNode::Repeat { node, spacing, count } => {
+                let last = (count.as_vec3a() - 1.).max(Vec3A::ZERO);
+                let spaced = spacing.cmpgt(Vec3A::ZERO);
+                let cell = Vec3A::select(spaced, (p / *spacing).round().clamp(Vec3A::ZERO, last), Vec3A::ZERO);
+                node.distance(p - *spacing * cell)
+            }
+            SdfNode::Twist { node, rate } => {
+                let xy = Mat2::from_angle(-rate * p.z) * p.truncate();
+                node.distance(xy.extend(p.z).into())
+            }
+            SdfNode::Displace { node, amplitude, frequency, octaves } => {
+                // Far from the surface, the noise can't bring it any closer than this, and
+                // skipping it makes the long steps up to the surface much cheaper.
+                let d = node.distance(p);
+                if d > 2. * amplitude.abs() {
+                    d - amplitude.abs()
+                } else {
+                    d + amplitude * fbm(p * *frequency, *octaves)
+                }
+            }
+        }
+    }
+
+    // A box that's sure to hold everything inside the surface.
+    pub fn bounds(&self) -> (Vec3A, Vec3A) {
+        match self {
+            SdfNode::Sphere { radius } => (Vec3A::splat(-radius), Vec3A::splat(*radius)),
+            SdfNode::Box { half_size, .. } => (-*half_size, *half_size),
+            SdfNode::Torus { major_radius, minor_radius } => {
+                let r = vec3a(major_radius + minor_radius, major_radius + minor_radius, *minor_radius);
+                (-r, r)
+            }
+            SdfNode::Cylinder { radius, height } => {
+                let r = vec3a(*radius, *radius, 0.5 * height);
+                (-r, r)
+            }
+            SdfNode::Capsule { radius, length } => {
+                let r = vec3a(*radius, *radius, 0.5 * length + radius);
+                (-r, r)
+            }
+            SdfNode::Transform { node, to_local, scale } => {
+                let (lo, hi) = node.bounds();
+                let to_world = to_local.inverse() * Affine3A::from_scale(Vec3::splat(*scale));
+                let center = to_world.transform_point3a(0.5 * (lo + hi));
+                let r = 0.5 * (hi - lo);
+                let m = to_world.matrix3;
+                let world_r = (m.x_axis * r.xxx()).abs() + (m.y_axis * r.yyy()).abs() + (m.z_axis * r.zzz()).abs();
+                (center - world_r, center + world_r)
+            }
+            // Smoothing can bulge out past either part, by up to a quarter of the smoothness.
+            SdfNode::Union { a, b, smoothness } => {
+                let ((a_lo, a_hi), (b_lo, b_hi)) = (a.bounds(), b.bounds());
+                grow((a_lo.min(b_lo), a_hi.max(b_hi)), 0.25 * smoothness)
+            }
+            SdfNode::Intersection { a, b, smoothness } => {
+                let ((a_lo, a_hi), (b_lo, b_hi)) = (a.bounds(), b.bounds());
+                let lo = a_lo.max(b_lo);
+                grow((lo, a_hi.min(b_hi).max(lo)), 0.25 * smoothness)
+            }
+            SdfNode::Difference { a, smoothness, .. } => grow(a.bounds(), 0.25 * smoothness),
+            SdfNode::Repeat { node, spacing, count } => {
+                let (lo, hi) = node.bounds();
+                let last = (count.as_vec3a() - 1.).max(Vec3A::ZERO);
+                (lo, hi + spacing.max(Vec3A::ZERO) * last)
+            }
+            SdfNode::Twist { node, .. } => {
+                let (lo, hi) = node.bounds();
+                let r = lo.truncate().length().max(hi.truncate().length()).max(vec2(lo.x, hi.y).length()).max(vec2(hi.x, lo.y).length());
+                (vec3a(-r, -r, lo.z), vec3a(r, r, hi.z))
+            }
+            SdfNode::Displace { node, amplitude, .. } => grow(node.bounds(), amplitude.abs()),
+        }
+    }
+
+    // How much faster than the true distance `distance` can change, at most. Twisting and
+    // displacing both bend space enough that it stops being exact, and stepping by the whole
+    // distance could jump right through thin parts.
+    pub fn lipschitz(&self) -> f32 {
+        match self {
+            SdfNode::Sphere { .. }
+            | SdfNode::Box { .. }
+            | SdfNode::Torus { .. }
+            | SdfNode::Cylinder { .. }
+            | SdfNode::Capsule { .. } => 1.,
+            SdfNode::Transform { node, .. } | SdfNode::Repeat { node, .. } => node.lipschitz(),
+            SdfNode::Union { a, b, .. } | SdfNode::Intersection { a, b, .. } | SdfNode::Difference { a, b, .. } => {
+                a.lipschitz().max(b.lipschitz())
+            }
+            SdfNode::Twist { node, rate } => {
+                let (lo, hi) = node.bounds();
+                let r = lo.truncate().abs().max(hi.truncate().abs()).length();
+                node.lipschitz() * (1. + (rate * r).powi(2)).sqrt()
+            }
+            SdfNode::Displace { node, amplitude, frequency, octaves } => {
+                node.lipschitz() + (amplitude * frequency).abs() * fbm_slope(*octaves)
+            }
+        }
+    }
+}
+
+fn grow((lo, hi): (Vec3A, Vec3A), by: f32) -> (Vec3A, Vec3A) {
+    (lo - Vec3A::splat(by), hi + Vec3A::splat(by))
+}
+
+// The polynomial smooth minimum, which blends between the two within `k` of each other.
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0. {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0., 1.);
+    b + (a - b) * h - k * h * (1. - h)
+}
+
+// A shape with no formula for where a ray hits it, only for how far any point is from it.
+// Rays are sphere traced: step forward by the distance to the surface, which can't overshoot
+// it, until that distance is tiny.
+#[derive(Debug, Clone)]
+pub struct Sdf {
+    root: SdfNode,
+    material: Arc<dyn Material>,
+    bounds: (Vec3A, Vec3A),
+    // The fraction of each distance that's safe to step.
+    step_scale: f32,
+}
+
+impl Sdf {
+    pub fn new(root: SdfNode, material: Arc<dyn Material>) -> Sdf {
+        let bounds = grow(root.bounds(), 1e-3);
+        let step_scale = 1. / root.lipschitz().max(1.);
+        Sdf { root, material, bounds, step_scale }
+    }
+
+    // Where the ray is within the bounding box, if anywhere.
+    fn clip(&self, ray: Ray) -> Option<(f32, f32)> {
+        let inv = ray.direction.recip();
+        let t0 = (self.bounds.0 - ray.origin) * inv;
+        let t1 = (self.bounds.1 - ray.origin) * inv;
+        let near = t0.min(t1).max_element().max(0.);
+        let far = t0.max(t1).min_element();
+        (near <= far).then_some((near, far))
+    }
+
+    // Outward, from the gradient of the distance, sampled at the corners of a tetrahedron.
+    fn normal(&self, p: Vec3A) -> Vec3A {
+        const H: f32 = 1e-4;
+        [vec3a(1., -1., -1.), vec3a(-1., -1., 1.), vec3a(-1., 1., -1.), vec3a(1., 1., 1.)]
+            .iter()
+            .map(|&k| k * self.root.distance(p + H * k))
+            .fold(Vec3A::ZERO, |sum, v| sum + v)
+            .normalize_or_zero()
+    }
+}
+
+impl Shape for Sdf {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
+        let (mut distance, far) = self.clip(ray)?;
+        let started_inside = self.root.distance(ray.at(distance)) < 0.;
+        let side = if started_inside { -1. } else { 1. };
+        for _ in 0..MAX_STEPS {
+            let d = side * self.root.distance(ray.at(distance));
+            if d < EPSILON && distance > MIN_HIT_DISTANCE {
+                let world_pos = ray.at(distance);
+                let normal = self.normal(world_pos);
+                return Some(Hit {
+                    world_pos,
+                    world_normal: normal,
+                    geometric_normal: normal,
+                    local_pos: world_pos,
+                    local_normal: normal,
+                    uv: vec2(
+                        0.5 + normal.y.atan2(normal.x) / std::f32::consts::TAU,
+                        normal.z.clamp(-1., 1.).acos() / std::f32::consts::PI,
+                    ),
+                    material: self.material.as_ref(),
+                    distance,
+                    started_inside,
+                    local_to_world: Mat3A::IDENTITY,
+                });
+            }
+            distance += (d * self.step_scale).max(EPSILON);
+            if distance > far {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        Some(self.bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::*;
+
+    // Sphere tracing should land where the exact intersection does.
+    #[test]
+    fn sphere_matches_analytic_sphere() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian(Arc::new(ConstantTexture(Vec3A::ONE))));
+        let center = vec3a(1., 2., 3.);
+        let node = SdfNode::Transform {
+            node: Box::new(SdfNode::Sphere { radius: 1.5 }),
+            to_local: Affine3A::from_translation(-Vec3::from(center)),
+            scale: 1.,
+        };
+        let sdf = Sdf::new(node, material.clone());
+        let sphere = Sphere { center, radius: 1.5, material };
+        let origin = vec3a(-4., 0., 5.);
+        for target in [center, center + vec3a(0.5, 1., 0.), center + vec3a(0., -0.3, -1.4)] {
+            let ray = Ray { origin, direction: (target - origin).normalize() };
+            let (traced, exact) = (sdf.trace_ray(ray).unwrap(), sphere.trace_ray(ray).unwrap());
+            assert!((traced.distance - exact.distance).abs() < 1e-4);
+            assert!(traced.world_normal.abs_diff_eq(exact.world_normal, 1e-3));
+            assert!(traced.geometric_normal.abs_diff_eq(exact.geometric_normal, 1e-3));
+        }
+    }
+}
@@ -17,21 +17,66 @@ use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
     io::Cursor,
+    sync::Arc,
 };
 
-pub trait Shape: std::fmt::Debug + dyn_clone::DynClone + Sync {
-    fn trace_ray(&self, ray: Ray) -> Option<Hit>;
+// A point picked on a shape's surface, for aiming shadow rays at lights. The pdf is over solid
+// angle, as seen from the point the sample was taken for.
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceSample {
+    pub position: Vec3A,
+    pub pdf: f32,
+}
+
+pub trait Shape: std::fmt::Debug + dyn_clone::DynClone + Send + Sync {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>>;
     fn get_bounds(&self) -> Option<(Vec3A, Vec3A)>;
+
+    // Whether the scene should sample this as a light. Only shapes that implement
+    // sample_surface and surface_pdf should ever say yes.
+    fn is_light(&self) -> bool {
+        false
+    }
+
+    fn sample_surface(&self, from: Vec3A, u: Vec2) -> Option<SurfaceSample> {
+        None
+    }
+
+    // The pdf sample_surface would have had for landing on `hit`, seen from `from`.
+    fn surface_pdf(&self, from: Vec3A, hit: &Hit) -> f32 {
+        0.
+    }
+
+    // Whether the shape encloses a volume, and so can answer trace_spans and be part of a Csg.
+    fn is_solid(&self) -> bool {
+        false
+    }
+
+    // Every stretch of the ray's line that's inside the shape, nearest first. This covers the
+    // whole line, so distances behind the ray's origin come out negative.
+    fn trace_spans(&self, ray: Ray) -> Vec<Span<'_>> {
+        Vec::new()
+    }
 }
 
+// Where a line passes into a solid shape and back out again. Normals point out of the shape at
+// both ends, and `started_inside` is set on the exit.
 #[derive(Debug, Clone, Copy)]
-pub struct Sphere<'a> {
+pub struct Span<'a> {
+    pub enter: Hit<'a>,
+    pub exit: Hit<'a>,
+}
+
+dyn_clone::clone_trait_object!(Shape);
+
+#[derive(Debug, Clone)]
+pub struct Sphere {
     pub center: Vec3A,
     pub radius: f32,
-    pub material: &'a dyn Material,
+    pub material: Arc<dyn Material>,
 }
 
-impl<'a> Sphere<'a> {
+impl Sphere {
     pub fn intersect(&self, r: Ray) -> Option<(f32, f32)> {
         let to_center = self.center - r.origin;
         let dir_dist_to_center = to_center.dot(r.direction);
@@ -47,8 +92,32 @@ impl<'a> Sphere<'a> {
     }
 }
 
-impl<'a> Shape for Sphere<'a> {
-    fn trace_ray(&self, ray: Ray) -> Option<Hit> {
+impl Sphere {
+    fn hit_at(&self, ray: Ray, distance: f32, started_inside: bool) -> Hit<'_> {
+        let world_pos = ray.at(distance);
+        let local_pos = world_pos - self.center;
+        let normal = local_pos.normalize_or_zero();
+        let uv = vec2(
+            0.5 + normal.y.atan2(normal.x) / std::f32::consts::TAU,
+            normal.z.clamp(-1., 1.).acos() / std::f32::consts::PI,
+        );
+        Hit {
+            world_pos,
+            world_normal: normal,
+            geometric_normal: normal,
+            local_pos,
+            local_normal: normal,
+            uv,
+            material: self.material.as_ref(),
+            distance,
+            started_inside,
+            local_to_world: Mat3A::IDENTITY,
+        }
+    }
+}
+
+impl Shape for Sphere {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
         if let Some(hit_range) = self.intersect(ray) {
             if hit_range.1 > 0. {
                 let started_inside = hit_range.0 < 0.;
@@ -57,19 +126,7 @@ impl<'a> Shape for Sphere<'a> {
                 } else {
                     hit_range.0
                 };
-                let world_pos = ray.at(distance);
-                let local_pos = world_pos - self.center;
-                let normal = local_pos.normalize_or_zero();
-                Some(Hit {
-                    world_pos,
-                    world_normal: normal,
-                    local_pos,
-                    local_normal: normal,
-                    material: self.material,
-                    distance,
-                    started_inside,
-                    local_to_world: Mat3A::IDENTITY,
-                })
+                Some(self.hit_at(ray, distance, started_inside))
             } else {
                 None
             }
@@ -81,28 +138,96 @@ impl<'a> Shape for Sphere<'a> {
     fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
         Some((
             self.center - Vec3A::splat(self.radius),
-            self.center - Vec3A::splat(self.radius),
+            self.center + Vec3A::splat(self.radius),
         ))
     }
+
+    fn is_light(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    // From outside, pick a direction in the cone the sphere covers. From inside, any point will do.
+    fn sample_surface(&self, from: Vec3A, u: Vec2) -> Option<SurfaceSample> {
+        let to_center = self.center - from;
+        let dist2 = to_center.length_squared();
+        let r2 = self.radius * self.radius;
+        if dist2 <= r2 {
+            let position = self.center + self.radius * uniform_sample_sphere(u);
+            let pdf = area_to_solid_angle_pdf(
+                1. / (2. * std::f32::consts::TAU * r2),
+                from,
+                position,
+                (position - self.center) / self.radius,
+            );
+            return Some(SurfaceSample { position, pdf });
+        }
+
+        let dist = dist2.sqrt();
+        let sin2_max = r2 / dist2;
+        let cos_max = (1. - sin2_max).max(0.).sqrt();
+        let one_minus_cos_max = sin2_max / (1. + cos_max);
+        let cos_theta = 1. - u.x * one_minus_cos_max;
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = std::f32::consts::TAU * u.y;
+        let axis = to_center / dist;
+        let (t, b) = orthonormal_basis(axis);
+        let direction = sin_theta * phi.cos() * t + sin_theta * phi.sin() * b + cos_theta * axis;
+        let near = dist * cos_theta - (r2 - dist2 * sin_theta * sin_theta).max(0.).sqrt();
+        Some(SurfaceSample {
+            position: from + near * direction,
+            pdf: 1. / (std::f32::consts::TAU * one_minus_cos_max),
+        })
+    }
+
+    fn surface_pdf(&self, from: Vec3A, hit: &Hit) -> f32 {
+        let dist2 = (self.center - from).length_squared();
+        let r2 = self.radius * self.radius;
+        if dist2 <= r2 {
+            area_to_solid_angle_pdf(
+                1. / (2. * std::f32::consts::TAU * r2),
+                from,
+                hit.world_pos,
+                hit.geometric_normal,
+            )
+        } else {
+            let sin2_max = r2 / dist2;
+            let cos_max = (1. - sin2_max).max(0.).sqrt();
+            1. / (std::f32::consts::TAU * sin2_max / (1. + cos_max))
+        }
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn trace_spans(&self, ray: Ray) -> Vec<Span<'_>> {
+        self.intersect(ray)
+            .map(|(near, far)| Span {
+                enter: self.hit_at(ray, near, false),
+                exit: self.hit_at(ray, far, true),
+            })
+            .into_iter()
+            .collect()
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct Plane<'a> {
+#[derive(Debug, Clone)]
+pub struct Plane {
     pub normal: Vec3A,
     pub center: Vec3A,
-    pub material: &'a dyn Material,
+    pub material: Arc<dyn Material>,
     n_dot_c: f32,
     right: Vec3A,
     up: Vec3A,
 }
 
-impl<'a> Plane<'a> {
+impl Plane {
     pub fn new(
         normal: Vec3A,
         right: Vec3A,
         center: Vec3A,
-        material: &'a dyn Material,
-    ) -> Plane<'a> {
+        material: Arc<dyn Material>,
+    ) -> Plane {
         let up = normal.cross(right);
         let right = up.cross(normal).normalize();
         let up = up.normalize();
@@ -118,8 +243,8 @@ impl<'a> Plane<'a> {
     }
 }
 
-impl<'a> Shape for Plane<'a> {
-    fn trace_ray(&self, ray: Ray) -> Option<Hit> {
+impl Shape for Plane {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
         let n_dot_dir = self.normal.dot(ray.direction);
         let n_dot_o = self.normal.dot(ray.origin);
         //dbg!(ray, n_dot_dir, n_dot_o);
@@ -128,25 +253,25 @@ impl<'a> Shape for Plane<'a> {
             if dist > 0. {
                 let world_pos = ray.at(dist);
                 let local_offset = world_pos - self.center;
+                let local_pos = Vec3A::new(
+                    self.right.dot(local_offset),
+                    self.up.dot(local_offset),
+                    0.,
+                );
                 //dbg!(dist, world_pos, local_offset);
+                let world_normal = if n_dot_o >= 0. { self.normal } else { -self.normal };
                 return Some(Hit {
                     world_pos,
-                    world_normal: if n_dot_o >= 0. {
-                        self.normal
-                    } else {
-                        -self.normal
-                    },
-                    local_pos: Vec3A::new(
-                        self.right.dot(local_offset),
-                        self.up.dot(local_offset),
-                        0.,
-                    ),
+                    world_normal,
+                    geometric_normal: world_normal,
+                    local_pos,
                     local_normal: if n_dot_o >= 0.0 {
                         Vec3A::Z
                     } else {
                         Vec3A::NEG_Z
                     },
-                    material: self.material,
+                    uv: local_pos.truncate(),
+                    material: self.material.as_ref(),
                     distance: dist,
                     started_inside: false,
                     local_to_world: Mat3A {
@@ -165,18 +290,18 @@ impl<'a> Shape for Plane<'a> {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct Cuboid<'a> {
+#[derive(Debug, Clone)]
+pub struct Cuboid {
     // You might have called this a "Box", except the word "Box" is already kind of a big deal in Rust.
     world_to_local: Affine3A,
     local_to_world: Affine3A,
     mins: Vec3A,
     maxs: Vec3A,
-    material: &'a dyn Material,
+    material: Arc<dyn Material>,
 }
 
-impl<'a> Cuboid<'a> {
-  pub fn new(origin: Vec3A, orient: Quat, mins: Vec3A, maxs: Vec3A, material: &'a dyn Material) -> Cuboid {
+impl Cuboid {
+  pub fn new(origin: Vec3A, orient: Quat, mins: Vec3A, maxs: Vec3A, material: Arc<dyn Material>) -> Cuboid {
     let local_to_world = Affine3A::from_rotation_translation(orient, origin.into());
     Cuboid {
       world_to_local: local_to_world.inverse(), 
@@ -188,75 +313,502 @@ impl<'a> Cuboid<'a> {
   }
 }
 
-impl<'a> Shape for Cuboid<'a> {
-    fn trace_ray(&self, ray: Ray) -> Option<Hit> {
+impl Cuboid {
+    fn hit_at(&self, local_origin: Vec3A, local_dir: Vec3A, dist: f32, started_inside: bool) -> Hit<'_> {
+        let local_pos = local_origin + dist * local_dir;
+        let mins_dist = (self.mins - local_pos).abs();
+        let maxs_dist = (self.maxs - local_pos).abs();
+        let mut best = mins_dist.x;
+        let mut local_norm = Vec3A::NEG_X;
+        let mut world_norm = -self.local_to_world.matrix3.x_axis;
+
+        if mins_dist.y < best {
+            best = mins_dist.y;
+            local_norm = Vec3A::NEG_Y;
+            world_norm = -self.local_to_world.matrix3.y_axis;
+        }
+
+        if mins_dist.z < best {
+            best = mins_dist.z;
+            local_norm = Vec3A::NEG_Z;
+            world_norm = -self.local_to_world.matrix3.z_axis;
+        }
+
+        if maxs_dist.x < best {
+            best = maxs_dist.x;
+            local_norm = Vec3A::X;
+            world_norm = self.local_to_world.matrix3.x_axis;
+        }
+
+        if maxs_dist.y < best {
+            best = maxs_dist.y;
+            local_norm = Vec3A::Y;
+            world_norm = self.local_to_world.matrix3.y_axis;
+        }
+
+        if maxs_dist.z < best {
+            //best = maxs_dist.z;
+            local_norm = Vec3A::Z;
+            world_norm = self.local_to_world.matrix3.z_axis;
+        }
+
+        // Texture coordinates run across the face that was hit, starting from its min corner.
+        let face_pos = local_pos - self.mins;
+        let uv = if local_norm.x != 0. {
+            face_pos.yz()
+        } else if local_norm.y != 0. {
+            face_pos.xz()
+        } else {
+            face_pos.xy()
+        };
+
+        Hit {
+            world_pos: self.local_to_world.transform_point3a(local_pos),
+            world_normal: world_norm,
+            geometric_normal: world_norm,
+            local_pos,
+            local_normal: local_norm,
+            uv,
+            material: self.material.as_ref(),
+            distance: dist,
+            started_inside,
+            local_to_world: self.local_to_world.matrix3,
+        }
+    }
+
+    // Distances along the ray to where it enters and leaves the box's slabs, in local space.
+    // The ray's in the box between them if the first is no bigger than the second.
+    fn slab_distances(&self, ray: Ray) -> (Vec3A, Vec3A, f32, f32) {
         let local_origin = self.world_to_local.transform_point3a(ray.origin);
         let local_dir = self.world_to_local.transform_vector3a(ray.direction);
         let a = (self.mins - local_origin) / local_dir;
         let b = (self.maxs - local_origin) / local_dir;
-        let near_dist = a.min(b).max_element();
-        let far_dist = a.max(b).min_element();
+        (local_origin, local_dir, a.min(b).max_element(), a.max(b).min_element())
+    }
+}
+
+impl Shape for Cuboid {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
+        let (local_origin, local_dir, near_dist, far_dist) = self.slab_distances(ray);
         if near_dist <= far_dist && far_dist > 0. {
             let started_inside = near_dist <= 0.;
             let dist = if started_inside { far_dist } else { near_dist };
-            let local_pos = local_origin + dist * local_dir;
-            let mins_dist = (self.mins - local_pos).abs();
-            let maxs_dist = (self.maxs - local_pos).abs();
-            let mut best = mins_dist.x;
-            let mut local_norm = Vec3A::NEG_X;
-            let mut world_norm = -self.local_to_world.matrix3.x_axis;
-
-            if mins_dist.y < best {
-                best = mins_dist.y;
-                local_norm = Vec3A::NEG_Y;
-                world_norm = -self.local_to_world.matrix3.y_axis;
+            Some(self.hit_at(local_origin, local_dir, dist, started_inside))
+        } else {
+            None
+        }
+    }
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let r = 0.5 * (self.maxs - self.mins);
+        let world_center = self.local_to_world.transform_point3a(self.mins + r);
+        let world_r = (self.local_to_world.matrix3.x_axis * r.xxx()).abs()
+            + (self.local_to_world.matrix3.y_axis * r.yyy()).abs()
+            + (self.local_to_world.matrix3.z_axis * r.zzz()).abs();
+        Some((world_center - world_r, world_center + world_r))
+    }
+
+    fn is_light(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    // Picks a face in proportion to its area, then a point on it.
+    fn sample_surface(&self, from: Vec3A, u: Vec2) -> Option<SurfaceSample> {
+        let size = self.maxs - self.mins;
+        let face_areas = [size.y * size.z, size.x * size.z, size.x * size.y];
+        let total_area = 2. * (face_areas[0] + face_areas[1] + face_areas[2]);
+        if total_area <= 0. {
+            return None;
+        }
+
+        // Flat boxes have faces with no area, which can never be picked. Rounding can leave
+        // `pick` past the end, so that lands on the last face that does have some area.
+        let mut pick = u.x * total_area;
+        let mut face = 0;
+        for i in (0..6).filter(|&i| face_areas[i / 2] > 0.) {
+            face = i;
+            if pick < face_areas[i / 2] {
+                break;
             }
+            pick -= face_areas[i / 2];
+        }
+        let axis = face / 2;
+        let s = (pick / face_areas[axis]).clamp(0., 1.);
+        let (a1, a2) = ((axis + 1) % 3, (axis + 2) % 3);
+
+        let mut local_pos = self.mins;
+        local_pos[axis] = if face % 2 == 0 { self.mins[axis] } else { self.maxs[axis] };
+        local_pos[a1] += s * size[a1];
+        local_pos[a2] += u.y * size[a2];
+        let mut local_normal = Vec3A::ZERO;
+        local_normal[axis] = 1.;
+
+        let position = self.local_to_world.transform_point3a(local_pos);
+        let normal = self.local_to_world.matrix3 * local_normal;
+        Some(SurfaceSample {
+            position,
+            pdf: area_to_solid_angle_pdf(1. / total_area, from, position, normal),
+        })
+    }
+
+    fn surface_pdf(&self, from: Vec3A, hit: &Hit) -> f32 {
+        let size = self.maxs - self.mins;
+        let total_area = 2. * (size.y * size.z + size.x * size.z + size.x * size.y);
+        area_to_solid_angle_pdf(1. / total_area, from, hit.world_pos, hit.geometric_normal)
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn trace_spans(&self, ray: Ray) -> Vec<Span<'_>> {
+        let (local_origin, local_dir, near_dist, far_dist) = self.slab_distances(ray);
+        if near_dist <= far_dist {
+            vec![Span {
+                enter: self.hit_at(local_origin, local_dir, near_dist, false),
+                exit: self.hit_at(local_origin, local_dir, far_dist, true),
+            }]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+// Another shape placed somewhere else, with its own rotation and scale, which needn't be the
+// same on every axis. The shape itself is shared, so a mesh can be placed many times over
+// without copying its triangles or BVH.
+#[derive(Debug, Clone)]
+pub struct Instance {
+    shape: Arc<dyn Shape>,
+    local_to_world: Affine3A,
+    world_to_local: Affine3A,
+    // Normals need the inverse transpose, or non-uniform scale would tilt them.
+    normal_to_world: Mat3A,
+}
+
+impl Instance {
+    pub fn new(shape: Arc<dyn Shape>, local_to_world: Affine3A) -> Instance {
+        let world_to_local = local_to_world.inverse();
+        Instance {
+            shape,
+            local_to_world,
+            world_to_local,
+            normal_to_world: world_to_local.matrix3.transpose(),
+        }
+    }
+
+    // The shapes all expect a unit direction, which means distances change scale too. Local
+    // distances need dividing by the scale that comes back with the ray.
+    fn local_ray(&self, ray: Ray) -> (Ray, f32) {
+        let local_dir = self.world_to_local.transform_vector3a(ray.direction);
+        let scale = local_dir.length();
+        let local_ray = Ray {
+            origin: self.world_to_local.transform_point3a(ray.origin),
+            direction: local_dir / scale,
+        };
+        (local_ray, scale)
+    }
+
+    fn world_hit<'a>(&self, hit: Hit<'a>, scale: f32) -> Hit<'a> {
+        Hit {
+            world_pos: self.local_to_world.transform_point3a(hit.world_pos),
+            world_normal: (self.normal_to_world * hit.world_normal).normalize_or_zero(),
+            geometric_normal: (self.normal_to_world * hit.geometric_normal).normalize_or_zero(),
+            distance: hit.distance / scale,
+            local_to_world: self.local_to_world.matrix3 * hit.local_to_world,
+            ..hit
+        }
+    }
+
+    // Solid angle gets stretched along with everything else, so a pdf over directions seen
+    // from inside the instance has to be scaled to be a pdf over directions in the world.
+    // `direction` is the unit world space direction.
+    fn solid_angle_scale(&self, direction: Vec3A) -> f32 {
+        let local = self.world_to_local.matrix3 * direction;
+        self.world_to_local.matrix3.determinant().abs() / local.length().powi(3)
+    }
+}
+
+impl Shape for Instance {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
+        let (local_ray, scale) = self.local_ray(ray);
+        Some(self.world_hit(self.shape.trace_ray(local_ray)?, scale))
+    }
+
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let (lo, hi) = self.shape.get_bounds()?;
+        let corners = (0..8).map(|i| {
+            let corner = Vec3A::select(BVec3A::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), hi, lo);
+            self.local_to_world.transform_point3a(corner)
+        });
+        Some(corners.fold(
+            (Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY)),
+            |(lo, hi), p| (lo.min(p), hi.max(p)),
+        ))
+    }
+
+    fn is_light(&self) -> bool {
+        self.shape.is_light()
+    }
+
+    fn sample_surface(&self, from: Vec3A, u: Vec2) -> Option<SurfaceSample> {
+        let local = self.shape.sample_surface(self.world_to_local.transform_point3a(from), u)?;
+        let position = self.local_to_world.transform_point3a(local.position);
+        let direction = (position - from).normalize_or_zero();
+        Some(SurfaceSample {
+            position,
+            pdf: local.pdf * self.solid_angle_scale(direction),
+        })
+    }
+
+    fn surface_pdf(&self, from: Vec3A, hit: &Hit) -> f32 {
+        let local_hit = Hit {
+            world_pos: self.world_to_local.transform_point3a(hit.world_pos),
+            world_normal: (self.local_to_world.matrix3.transpose() * hit.world_normal).normalize_or_zero(),
+            geometric_normal: (self.local_to_world.matrix3.transpose() * hit.geometric_normal).normalize_or_zero(),
+            ..*hit
+        };
+        let local_from = self.world_to_local.transform_point3a(from);
+        let direction = (hit.world_pos - from).normalize_or_zero();
+        self.shape.surface_pdf(local_from, &local_hit) * self.solid_angle_scale(direction)
+    }
+
+    fn is_solid(&self) -> bool {
+        self.shape.is_solid()
+    }
+
+    fn trace_spans(&self, ray: Ray) -> Vec<Span<'_>> {
+        let (local_ray, scale) = self.local_ray(ray);
+        self.shape
+            .trace_spans(local_ray)
+            .into_iter()
+            .map(|span| Span {
+                enter: self.world_hit(span.enter, scale),
+                exit: self.world_hit(span.exit, scale),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    // What's in the first shape but not the second.
+    Difference,
+}
+
+impl CsgOp {
+    fn inside(self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            CsgOp::Union => in_a || in_b,
+            CsgOp::Intersection => in_a && in_b,
+            CsgOp::Difference => in_a && !in_b,
+        }
+    }
+}
+
+// Two solid shapes combined into one. Each part of the surface keeps the material of the shape
+// it came from, so the inside of a bite taken out of something is the material of whatever
+// took the bite.
+#[derive(Debug, Clone)]
+pub struct Csg {
+    op: CsgOp,
+    a: Box<dyn Shape>,
+    b: Box<dyn Shape>,
+}
+
+impl Csg {
+    // Both shapes have to be solid.
+    pub fn new(op: CsgOp, a: Box<dyn Shape>, b: Box<dyn Shape>) -> Csg {
+        debug_assert!(a.is_solid() && b.is_solid());
+        Csg { op, a, b }
+    }
+}
 
-            if mins_dist.z < best {
-                best = mins_dist.z;
-                local_norm = Vec3A::NEG_Z;
-                world_norm = -self.local_to_world.matrix3.z_axis;
+impl Shape for Csg {
+    fn trace_ray(&self, ray: Ray) -> Option<Hit<'_>> {
+        self.trace_spans(ray).into_iter().find_map(|span| {
+            if span.enter.distance > 0. {
+                Some(span.enter)
+            } else if span.exit.distance > 0. {
+                Some(span.exit)
+            } else {
+                None
             }
+        })
+    }
 
-            if maxs_dist.x < best {
-                best = maxs_dist.x;
-                local_norm = Vec3A::X;
-                world_norm = self.local_to_world.matrix3.x_axis;
+    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
+        let a = self.a.get_bounds();
+        let b = self.b.get_bounds();
+        match self.op {
+            CsgOp::Union => {
+                let ((a_lo, a_hi), (b_lo, b_hi)) = (a?, b?);
+                Some((a_lo.min(b_lo), a_hi.max(b_hi)))
             }
+            CsgOp::Intersection => match (a, b) {
+                (Some((a_lo, a_hi)), Some((b_lo, b_hi))) => Some((a_lo.max(b_lo), a_hi.min(b_hi))),
+                (a, b) => a.or(b),
+            },
+            CsgOp::Difference => a,
+        }
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
 
-            if maxs_dist.y < best {
-                best = maxs_dist.y;
-                local_norm = Vec3A::Y;
-                world_norm = self.local_to_world.matrix3.y_axis;
+    // Walks along the line through every place it crosses either shape's surface, keeping track
+    // of whether it's in each, and keeps the crossings where that changes whether it's in the
+    // combination.
+    fn trace_spans(&self, ray: Ray) -> Vec<Span<'_>> {
+        let mut crossings: Vec<(Hit, bool, bool)> = Vec::new();
+        for (spans, from_a) in [(self.a.trace_spans(ray), true), (self.b.trace_spans(ray), false)] {
+            for span in spans {
+                crossings.push((span.enter, from_a, true));
+                crossings.push((span.exit, from_a, false));
             }
+        }
+        crossings.sort_by(|x, y| x.0.distance.total_cmp(&y.0.distance));
 
-            if maxs_dist.z < best {
-                //best = maxs_dist.z;
-                local_norm = Vec3A::Z;
-                world_norm = self.local_to_world.matrix3.z_axis;
+        let (mut in_a, mut in_b, mut inside) = (false, false, false);
+        let mut enter = None;
+        let mut spans = Vec::new();
+        for (hit, from_a, entering) in crossings {
+            if from_a {
+                in_a = entering;
+            } else {
+                in_b = entering;
+            }
+            if self.op.inside(in_a, in_b) == inside {
+                continue;
             }
+            inside = !inside;
+            // Going into one shape can mean coming out of the combination, like where the first
+            // shape has had a bite taken out of it. Then the surface faces the other way.
+            let hit = if entering == inside {
+                hit
+            } else {
+                Hit {
+                    world_normal: -hit.world_normal,
+                    geometric_normal: -hit.geometric_normal,
+                    local_normal: -hit.local_normal,
+                    ..hit
+                }
+            };
+            if inside {
+                enter = Some(Hit { started_inside: false, ..hit });
+            } else if let Some(enter) = enter.take() {
+                spans.push(Span { enter, exit: Hit { started_inside: true, ..hit } });
+            }
+        }
+        spans
+    }
+}
 
-            Some(Hit {
-                world_pos: self.local_to_world.transform_point3a(local_pos),
-                world_normal: world_norm,
-                local_pos: local_pos,
-                local_normal: local_norm,
-                material: self.material,
-                distance: dist,
-                started_inside: started_inside,
-                local_to_world: self.local_to_world.matrix3,
-            })
-        } else {
-            None
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::*;
+
+    fn material() -> Arc<dyn Material> {
+        Arc::new(Lambertian(Arc::new(ConstantTexture(Vec3A::ONE))))
+    }
+
+    fn is_material(hit: &Hit, material: &Arc<dyn Material>) -> bool {
+        std::ptr::eq(hit.material as *const dyn Material as *const u8, Arc::as_ptr(material) as *const u8)
+    }
+
+    fn sphere(center: Vec3A, material: &Arc<dyn Material>) -> Box<dyn Shape> {
+        Box::new(Sphere { center, radius: 1., material: material.clone() })
+    }
+
+    fn span_distances(shape: &dyn Shape, ray: Ray) -> Vec<(f32, f32)> {
+        shape.trace_spans(ray).iter().map(|s| (s.enter.distance, s.exit.distance)).collect()
+    }
+
+    // A unit sphere with everything past x = 0.5 bitten off, looked at straight into the bite.
+    #[test]
+    fn difference_shows_the_bite() {
+        let (ball, cutter) = (material(), material());
+        let cuboid = Cuboid::new(Vec3A::ZERO, Quat::IDENTITY, vec3a(0.5, -2., -2.), vec3a(2., 2., 2.), cutter.clone());
+        let csg = Csg::new(CsgOp::Difference, sphere(Vec3A::ZERO, &ball), Box::new(cuboid));
+        let ray = Ray { origin: vec3a(3., 0., 0.), direction: -Vec3A::X };
+
+        let hit = csg.trace_ray(ray).unwrap();
+        assert!((hit.distance - 2.5).abs() < 1e-5);
+        assert!(is_material(&hit, &cutter));
+        assert!(hit.world_normal.abs_diff_eq(Vec3A::X, 1e-5));
+        assert!(hit.geometric_normal.abs_diff_eq(Vec3A::X, 1e-5));
+
+        let spans = csg.trace_spans(ray);
+        assert_eq!(spans.len(), 1);
+        let Span { enter, exit } = spans[0];
+        assert!(!enter.started_inside && exit.started_inside);
+        assert!((exit.distance - 4.).abs() < 1e-5);
+        assert!(is_material(&exit, &ball));
+        assert!(exit.world_normal.abs_diff_eq(-Vec3A::X, 1e-5));
+    }
+
+    // A unit sphere stretched into an ellipsoid, checked against intersecting the ellipsoid
+    // directly.
+    #[test]
+    fn instance_scales_a_sphere_into_an_ellipsoid() {
+        let (center, rotation, scale) = (vec3a(1., -2., 0.5), Quat::from_rotation_z(0.3), vec3a(1., 2., 0.5));
+        let instance = Instance::new(
+            Arc::new(Sphere { center: Vec3A::ZERO, radius: 1., material: material() }),
+            Affine3A::from_scale_rotation_translation(scale.into(), rotation, center.into()),
+        );
+        let from = vec3a(6., 3., 2.);
+        for target in [center, vec3a(1.5, -1., 0.6), vec3a(0.5, -3., 0.3)] {
+            let direction = (target - from).normalize();
+            let hit = instance.trace_ray(Ray { origin: from, direction }).unwrap();
+
+            let o = (rotation.inverse() * (from - center)) / scale;
+            let d = (rotation.inverse() * direction) / scale;
+            let (a, b, c) = (d.dot(d), 2. * o.dot(d), o.dot(o) - 1.);
+            let distance = (-b - (b * b - 4. * a * c).sqrt()) / (2. * a);
+            let local = rotation.inverse() * (from + distance * direction - center);
+            let normal = (rotation * (local / (scale * scale))).normalize();
+            assert!((hit.distance - distance).abs() < 1e-4);
+            assert!(hit.world_normal.abs_diff_eq(normal, 1e-4));
+            assert!(hit.geometric_normal.abs_diff_eq(normal, 1e-4));
         }
     }
 
-    fn get_bounds(&self) -> Option<(Vec3A, Vec3A)> {
-        let r = 0.5 * (self.maxs - self.mins);
-        let world_center = self.local_to_world.transform_point3a(self.mins + r);
-        let world_r = (self.local_to_world.matrix3.x_axis * r.xxx()).abs()
-            + (self.local_to_world.matrix3.y_axis * r.yyy()).abs()
-            + (self.local_to_world.matrix3.z_axis * r.zzz()).abs();
-        Some((world_center - world_r, world_center + world_r))
+    // Where the instance says it sampled, surface_pdf should give back the same pdf.
+    #[test]
+    fn instance_sample_pdf_matches_surface_pdf() {
+        let instance = Instance::new(
+            Arc::new(Sphere { center: Vec3A::ZERO, radius: 1., material: material() }),
+            Affine3A::from_scale_rotation_translation(vec3(1., 2., 0.5), Quat::from_rotation_z(0.3), vec3(1., -2., 0.5)),
+        );
+        for from in [vec3a(6., 3., 2.), vec3a(1., -2., 0.5)] {
+            for u in [vec2(0.1, 0.2), vec2(0.5, 0.5), vec2(0.9, 0.7)] {
+                let sample = instance.sample_surface(from, u).unwrap();
+                let direction = (sample.position - from).normalize();
+                let hit = instance.trace_ray(Ray { origin: from, direction }).unwrap();
+                assert!(hit.world_pos.abs_diff_eq(sample.position, 1e-4));
+                let pdf = instance.surface_pdf(from, &hit);
+                assert!((pdf - sample.pdf).abs() < 1e-4 * sample.pdf);
+            }
+        }
+    }
+
+    // Two unit spheres a unit apart, along the line through both centers.
+    #[test]
+    fn union_and_intersection_merge_spans() {
+        let ray = Ray { origin: vec3a(-3., 0., 0.), direction: Vec3A::X };
+        let pair = |op| Csg::new(op, sphere(Vec3A::ZERO, &material()), sphere(Vec3A::X, &material()));
+        for (op, expected) in [(CsgOp::Union, (2., 5.)), (CsgOp::Intersection, (3., 4.))] {
+            let csg = pair(op);
+            let spans = span_distances(&csg, ray);
+            assert_eq!(spans.len(), 1);
+            assert!((spans[0].0 - expected.0).abs() < 1e-5 && (spans[0].1 - expected.1).abs() < 1e-5);
+            let Span { enter, exit } = csg.trace_spans(ray)[0];
+            assert!(!enter.started_inside && exit.started_inside);
+            assert!(enter.world_normal.abs_diff_eq(-Vec3A::X, 1e-5) && exit.world_normal.abs_diff_eq(Vec3A::X, 1e-5));
+        }
     }
 }
@@ -0,0 +1,154 @@
+use crate::geom::*;
+use crate::noise::*;
+
+use glam::{f32::*, *};
+use image::Rgb32FImage;
+use std::sync::Arc;
+
+// A value that varies over a surface. Colors use all three channels, and single numbers like
+// gloss_size use `value`, which for anything gray is the same thing.
+pub trait Texture: std::fmt::Debug + dyn_clone::DynClone + Send + Sync {
+  fn color(&self, hit: &Hit) -> Vec3A;
+
+  fn value(&self, hit: &Hit) -> f32 {
+    let c = self.color(hit);
+    (c.x + c.y + c.z) / 3.
+  }
+}
+
+dyn_clone::clone_trait_object!(Texture);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConstantTexture(pub Vec3A);
+
+impl Texture for ConstantTexture {
+  fn color(&self, hit: &Hit) -> Vec3A {
+    self.0
+  }
+}
+
+// How an image is looked up between texel centers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextureFilter {
+  Nearest,
+  Bilinear,
+}
+
+// Where on the image a point on the surface lands, in whole-image units, so 0..1 covers it once.
+// It repeats in both directions.
+#[derive(Debug, Clone, Copy)]
+pub enum Projection {
+  // The shape's own texture coordinates.
+  Uv { scale: Vec2, offset: Vec2 },
+  // Flat across the shape's local space, like a slide projector. Rows and columns of the
+  // matrix give u and v.
+  Planar { local_to_uv: Affine3A },
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageTexture {
+  width: usize,
+  height: usize,
+  // Linear RGB, top row first.
+  pixels: Vec<Vec3A>,
+  pub projection: Projection,
+  pub filter: TextureFilter,
+  // Everything read from the image is multiplied by this.
+  pub intensity: f32,
+}
+
+impl ImageTexture {
+  // `image` holds linear values.
+  pub fn new(image: &Rgb32FImage, projection: Projection, filter: TextureFilter, intensity: f32) -> ImageTexture {
+    ImageTexture {
+      width: image.width() as usize,
+      height: image.height() as usize,
+      pixels: image.pixels().map(|p| Vec3A::from(p.0)).collect(),
+      projection,
+      filter,
+      intensity,
+    }
+  }
+
+  fn texel(&self, x: i64, y: i64) -> Vec3A {
+    let x = x.rem_euclid(self.width as i64) as usize;
+    let y = y.rem_euclid(self.height as i64) as usize;
+    self.pixels[y * self.width + x]
+  }
+}
+
+impl Texture for ImageTexture {
+  fn color(&self, hit: &Hit) -> Vec3A {
+    let uv = match self.projection {
+      Projection::Uv { scale, offset } => hit.uv * scale + offset,
+      Projection::Planar { local_to_uv } => local_to_uv.transform_point3a(hit.local_pos).truncate(),
+    };
+    let pos = uv * vec2(self.width as f32, self.height as f32);
+    let color = match self.filter {
+      TextureFilter::Nearest => self.texel(pos.x.floor() as i64, pos.y.floor() as i64),
+      TextureFilter::Bilinear => {
+        // Texel centers are at the halves.
+        let pos = pos - 0.5;
+        let corner = pos.floor();
+        let f = pos - corner;
+        let (x, y) = (corner.x as i64, corner.y as i64);
+        let top = self.texel(x, y).lerp(self.texel(x + 1, y), f.x);
+        let bottom = self.texel(x, y + 1).lerp(self.texel(x + 1, y + 1), f.x);
+        top.lerp(bottom, f.y)
+      }
+    };
+    self.intensity * color
+  }
+}
+
+// A 3D checkerboard in the shape's local space, with cubes `size` across, like the Checkerboard
+// material but for a single value.
+#[derive(Debug, Clone)]
+pub struct CheckerTexture {
+  pub size: f32,
+  pub a: Arc<dyn Texture>,
+  pub b: Arc<dyn Texture>,
+}
+
+impl Texture for CheckerTexture {
+  fn color(&self, hit: &Hit) -> Vec3A {
+    let c = (hit.local_pos / self.size).floor();
+    if (c.x as i32 ^ c.y as i32 ^ c.z as i32) & 1 == 0 {
+      self.a.color(hit)
+    } else {
+      self.b.color(hit)
+    }
+  }
+}
+
+// Fractal noise in the shape's local space, blending from `a` where it's lowest to `b` where
+// it's highest.
+#[derive(Debug, Clone)]
+pub struct NoiseTexture {
+  pub frequency: f32,
+  pub octaves: u32,
+  pub a: Arc<dyn Texture>,
+  pub b: Arc<dyn Texture>,
+}
+
+impl Texture for NoiseTexture {
+  fn color(&self, hit: &Hit) -> Vec3A {
+    let t = (0.5 + 0.5 * fbm(hit.local_pos * self.frequency, self.octaves)).clamp(0., 1.);
+    self.a.color(hit).lerp(self.b.color(hit), t)
+  }
+}
+
+// `a` where `amount` is 0, `b` where it's 1, and a blend of the two in between.
+#[derive(Debug, Clone)]
+pub struct MixTexture {
+  pub a: Arc<dyn Texture>,
+  pub b: Arc<dyn Texture>,
+  pub amount: Arc<dyn Texture>,
+}
+
+impl Texture for MixTexture {
+  fn color(&self, hit: &Hit) -> Vec3A {
+    let t = self.amount.value(hit).clamp(0., 1.);
+    self.a.color(hit).lerp(self.b.color(hit), t)
+  }
+}